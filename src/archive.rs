@@ -0,0 +1,303 @@
+/// MAP-Elites quality-diversity archive
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use vmmc::polygons::{calc_polygon_count, calc_polygon_distribution};
+use vmmc::vmmc::Vmmc;
+use vmmc::{Prng, SimParams};
+
+use crate::engine::{megasteps_run, EvoEngine};
+use crate::fitness::{calculate_bond_order_matrix, require_two_species};
+use crate::io::{write_archive_png, write_archive_toml};
+use crate::nn::Dna;
+use crate::schedule::ScheduleState;
+use crate::structure::{cluster_sizes, largest_cluster_fraction};
+
+/// Behaviour descriptors computed from the final state of a simulation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Descriptor {
+    PolygonCount,
+    ParticleCount,
+    // fraction of polygons at the given index of the polygon distribution
+    PolygonFraction(usize),
+    // entry of the normalized 2-vertex bond order matrix
+    BondOrderFraction(usize, usize),
+    // fraction of particles in the largest cluster
+    LargestClusterFraction,
+}
+
+impl Descriptor {
+    fn validate(&self, sim_params: &SimParams) -> Result<()> {
+        if let Descriptor::BondOrderFraction(row, col) = self {
+            if *row > 1 || *col > 1 {
+                bail!("BondOrderFraction({row}, {col}) is outside the 2x2 bond order matrix");
+            }
+            require_two_species("BondOrderFraction", sim_params)?;
+        }
+        Ok(())
+    }
+
+    pub fn eval(&self, vmmc: &Vmmc) -> f64 {
+        match self {
+            Descriptor::PolygonCount => calc_polygon_count(vmmc, 12) as f64,
+            Descriptor::ParticleCount => vmmc.particles().iter().count() as f64,
+            Descriptor::PolygonFraction(idx) => {
+                let dist = calc_polygon_distribution(vmmc, 12);
+                let total = dist.iter().sum::<usize>();
+                if total == 0 {
+                    0.0
+                } else {
+                    dist.get(*idx).copied().unwrap_or(0) as f64 / total as f64
+                }
+            }
+            Descriptor::BondOrderFraction(row, col) => {
+                let matrix = calculate_bond_order_matrix(vmmc);
+                let v = match (row, col) {
+                    (0, 0) => matrix.0 .0,
+                    (0, 1) => matrix.0 .1,
+                    (1, 0) => matrix.1 .0,
+                    (1, 1) => matrix.1 .1,
+                    _ => {
+                        unreachable!("bond order indices are checked by MapElitesConfig::validate")
+                    }
+                };
+                // no bonds at all normalizes to NaN
+                if v.is_nan() {
+                    0.0
+                } else {
+                    v
+                }
+            }
+            Descriptor::LargestClusterFraction => largest_cluster_fraction(&cluster_sizes(vmmc)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DescriptorAxis {
+    pub descriptor: Descriptor,
+    pub min: f64,
+    pub max: f64,
+    pub bins: usize,
+}
+
+impl DescriptorAxis {
+    fn validate(&self, sim_params: &SimParams) -> Result<()> {
+        if self.bins == 0 {
+            bail!("{:?} axis needs at least one bin", self.descriptor);
+        }
+        if self.max <= self.min {
+            bail!(
+                "{:?} axis has max = {} <= min = {}",
+                self.descriptor,
+                self.max,
+                self.min
+            );
+        }
+        self.descriptor.validate(sim_params)
+    }
+
+    // values outside of [min, max] are clamped into the edge bins
+    pub fn bin(&self, value: f64) -> usize {
+        let t = (value - self.min) / (self.max - self.min);
+        ((t * self.bins as f64).floor().max(0.0) as usize).min(self.bins - 1)
+    }
+
+    pub fn bin_bounds(&self, bin: usize) -> (f64, f64) {
+        let width = (self.max - self.min) / self.bins as f64;
+        (
+            self.min + width * bin as f64,
+            self.min + width * (bin + 1) as f64,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MapElitesConfig {
+    pub x: DescriptorAxis,
+    pub y: DescriptorAxis,
+}
+
+impl MapElitesConfig {
+    pub fn validate(&self, sim_params: &SimParams) -> Result<()> {
+        self.x.validate(sim_params).context("MapElites x axis")?;
+        self.y.validate(sim_params).context("MapElites y axis")
+    }
+
+    pub fn describe(&self, vmmc: &Vmmc) -> (f64, f64) {
+        (self.x.descriptor.eval(vmmc), self.y.descriptor.eval(vmmc))
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Elite {
+    pub cell: (usize, usize),
+    pub descriptors: (f64, f64),
+    pub fitness: f64,
+    pub polygons: usize,
+    pub generation: usize,
+    pub path: String,
+    pub dna: Dna,
+}
+
+pub struct Archive {
+    config: MapElitesConfig,
+    elites: BTreeMap<(usize, usize), Elite>,
+}
+
+impl Archive {
+    pub fn new(config: MapElitesConfig) -> Self {
+        Self {
+            config,
+            elites: BTreeMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &MapElitesConfig {
+        &self.config
+    }
+
+    pub fn elites(&self) -> impl Iterator<Item = &Elite> {
+        self.elites.values()
+    }
+
+    pub fn num_cells(&self) -> usize {
+        self.config.x.bins * self.config.y.bins
+    }
+
+    pub fn len(&self) -> usize {
+        self.elites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elites.is_empty()
+    }
+
+    // The elites as a genepool, for checkpoints
+    pub fn genepool(&self) -> Vec<(Dna, f64, usize)> {
+        self.elites()
+            .map(|e| (e.dna.clone(), e.fitness, e.polygons))
+            .collect()
+    }
+
+    // Returns true if the candidate became the new elite of its cell
+    pub fn insert(
+        &mut self,
+        dna: &Dna,
+        descriptors: (f64, f64),
+        fitness: f64,
+        polygons: usize,
+        generation: usize,
+        path: String,
+    ) -> bool {
+        let cell = (
+            self.config.x.bin(descriptors.0),
+            self.config.y.bin(descriptors.1),
+        );
+        if let Some(elite) = self.elites.get(&cell) {
+            if elite.fitness >= fitness {
+                return false;
+            }
+        }
+        let elite = Elite {
+            cell,
+            descriptors,
+            fitness,
+            polygons,
+            generation,
+            path,
+            dna: dna.clone(),
+        };
+        self.elites.insert(cell, elite);
+        true
+    }
+
+    // Pick a parent uniformly from the occupied cells
+    pub fn select(&self, rng: &mut Prng) -> &Dna {
+        let idx = rng.random_range(0..self.elites.len());
+        &self.elites.values().nth(idx).unwrap().dna
+    }
+}
+
+impl EvoEngine {
+    pub(crate) fn step_map_elites_and_save(
+        &mut self,
+        config: MapElitesConfig,
+        output_dir: &str,
+        rng: &mut Prng,
+    ) {
+        let mut archive = Archive::new(config);
+        self.schedule_state = ScheduleState::new(self.mutation_factor);
+        let mut candidates = self.initial_candidates();
+
+        for gen_idx in 0..self.num_generations {
+            log::info!("Starting generation {gen_idx}: ");
+            let ids: Vec<usize> = candidates.iter().map(|c| c.id()).collect();
+            log::info!("Candidates: {:?}", ids);
+
+            let start = Instant::now();
+            let gen_dir = format!("./{output_dir}/{:0>3}", gen_idx);
+            let children = self.step_generation_to(&candidates, self.fitness(), rng, &gen_dir);
+            log::info!("Generation execution time: {:?}", Instant::now() - start);
+
+            let fitnesses = self.get_fitnesses(&children);
+            self.record_hall_of_fame(&candidates, &fitnesses, gen_idx, &gen_dir, output_dir);
+            let mut new_elites = 0;
+            for (idx, (dna, child)) in candidates.iter().zip(children.iter()).enumerate() {
+                // failed and cancelled children have no descriptors, eliminated ones are not
+                // comparable to full runs
+                if child.fitness().is_none() {
+                    continue;
+                }
+                let Some(summary) = &child.summary else {
+                    continue;
+                };
+                let Some(descriptors) = summary.descriptors else {
+                    continue;
+                };
+                let path = format!("{gen_dir}/{:0>3}", idx);
+                let polygons = summary.polygons;
+                if archive.insert(dna, descriptors, fitnesses[idx], polygons, gen_idx, path) {
+                    new_elites += 1;
+                }
+            }
+            log::info!(
+                "Archive: {new_elites} new elites, {}/{} cells filled",
+                archive.len(),
+                archive.num_cells()
+            );
+            self.record_checkpoint(output_dir, gen_idx, &[archive.genepool().as_slice()]);
+
+            let mutation_factor = self.schedule_state.update(
+                &self.mutation_schedule,
+                gen_idx,
+                self.num_generations,
+                &fitnesses,
+            );
+            log::info!("Mutation factor for next generation: {mutation_factor}");
+
+            if self.should_stop(&fitnesses, megasteps_run(&children)) {
+                break;
+            }
+            if self.is_cancelled() {
+                log::info!("Run cancelled after generation {gen_idx}");
+                break;
+            }
+
+            candidates = (0..self.generation_size())
+                .map(|_| {
+                    let parent = archive.select(rng).clone();
+                    self.spawn_child(&parent)
+                })
+                .collect();
+        }
+
+        if let Err(err) = write_archive_toml(&archive, &format!("{output_dir}/archive.toml")) {
+            log::warn!("Unable to write archive: {err:#}");
+        }
+        write_archive_png(&archive, &format!("{output_dir}/archive.png"));
+    }
+}
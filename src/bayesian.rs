@@ -0,0 +1,362 @@
+/// Bayesian optimisation of low-dimensional protocols with a Gaussian-process surrogate
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use vmmc::protocol::SynthesisProtocol;
+use vmmc::Prng;
+
+use crate::engine::{megasteps_run, EvoEngine};
+use crate::io::write_posterior_toml;
+use crate::nn::fixed::FixedProtocol;
+use crate::nn::Dna;
+use crate::surrogate::{expected_improvement, upper_confidence_bound, GaussianProcess};
+
+fn default_num_proposals() -> usize {
+    1000
+}
+
+fn default_length_scale() -> f64 {
+    1.0
+}
+
+fn default_noise() -> f64 {
+    1e-2
+}
+
+// points per parameter in the posterior slices
+const SLICE_POINTS: usize = 51;
+
+/// Parameters searched over, each given as a `[min, max]` range
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SearchSpace {
+    // constant protocol, with the length of `init_protocol` unless `num_megasteps` is searched
+    Flat {
+        chemical_potential: (f64, f64),
+        interaction_energy: (f64, f64),
+        #[serde(default)]
+        num_megasteps: Option<(usize, usize)>,
+    },
+    // piecewise linear protocol from the start of `init_protocol`, one
+    // epsilon and one mu slope per phase
+    Phases {
+        num_phases: usize,
+        slope: (f64, f64),
+    },
+}
+
+impl SearchSpace {
+    fn ranges(&self) -> Vec<(f64, f64)> {
+        match self {
+            SearchSpace::Flat {
+                chemical_potential,
+                interaction_energy,
+                num_megasteps,
+            } => {
+                let mut ranges = vec![*chemical_potential, *interaction_energy];
+                // widened by half a megastep so both ends are as likely as the lengths between
+                if let Some((min, max)) = num_megasteps {
+                    ranges.push((*min as f64 - 0.5, *max as f64 + 0.5));
+                }
+                ranges
+            }
+            SearchSpace::Phases { num_phases, slope } => vec![*slope; 2 * num_phases],
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        match self {
+            SearchSpace::Flat { num_megasteps, .. } => {
+                let mut names = vec!["chemical_potential".into(), "interaction_energy".into()];
+                if num_megasteps.is_some() {
+                    names.push("num_megasteps".into());
+                }
+                names
+            }
+            SearchSpace::Phases { num_phases, .. } => (0..*num_phases)
+                .map(|i| format!("epsilon_slope{i}"))
+                .chain((0..*num_phases).map(|i| format!("mu_slope{i}")))
+                .collect(),
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.ranges().len()
+    }
+
+    // Maps a point of the unit cube onto the parameter ranges
+    pub fn scale(&self, x: &[f64]) -> Vec<f64> {
+        self.ranges()
+            .iter()
+            .zip(x)
+            .map(|((min, max), x)| min + x * (max - min))
+            .collect()
+    }
+
+    pub fn protocol(&self, x: &[f64], init_protocol: &SynthesisProtocol) -> Result<FixedProtocol> {
+        let params = self.scale(x);
+        match self {
+            SearchSpace::Flat { num_megasteps, .. } => {
+                let len = match num_megasteps {
+                    Some((min, max)) => (params[2].round() as usize).clamp(*min, *max),
+                    None => init_protocol.len(),
+                };
+                Ok(FixedProtocol::flat(params[0], params[1], len))
+            }
+            SearchSpace::Phases { num_phases, .. } => {
+                let (epsilon_slopes, mu_slopes) = params.split_at(*num_phases);
+                FixedProtocol::from_slopes(init_protocol, epsilon_slopes, mu_slopes)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Acquisition {
+    #[default]
+    ExpectedImprovement,
+    // exploration weight on the posterior standard deviation
+    UpperConfidenceBound(f64),
+}
+
+impl Acquisition {
+    fn score(&self, mean: f64, std: f64, best: f64) -> f64 {
+        match self {
+            Acquisition::ExpectedImprovement => expected_improvement(mean, std, best),
+            Acquisition::UpperConfidenceBound(beta) => upper_confidence_bound(mean, std, *beta),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BayesianConfig {
+    pub space: SearchSpace,
+    #[serde(default)]
+    pub acquisition: Acquisition,
+    // simulations per generation, defaults to the number of cores
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    // random points scored by the acquisition function for every proposal
+    #[serde(default = "default_num_proposals")]
+    pub num_proposals: usize,
+    // RBF length scale, in units of the per-parameter standard deviation
+    #[serde(default = "default_length_scale")]
+    pub length_scale: f64,
+    // observation noise relative to the fitness variance
+    #[serde(default = "default_noise")]
+    pub noise: f64,
+}
+
+impl BayesianConfig {
+    pub fn validate(&self) -> Result<()> {
+        match self.space {
+            SearchSpace::Phases { num_phases: 0, .. } => {
+                bail!("Bayesian Phases search space needs at least one phase")
+            }
+            SearchSpace::Flat {
+                num_megasteps: Some((min, max)),
+                ..
+            } if min == 0 || min > max => {
+                bail!("Bayesian Flat num_megasteps needs 1 <= min <= max, got [{min}, {max}]")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PosteriorObservation {
+    pub params: Vec<f64>,
+    pub fitness: f64,
+}
+
+// Posterior along one parameter with the others held at the best observed point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PosteriorSlice {
+    pub parameter: String,
+    pub values: Vec<f64>,
+    pub mean: Vec<f64>,
+    pub std: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Posterior {
+    pub parameters: Vec<String>,
+    pub best_params: Vec<f64>,
+    pub best_fitness: f64,
+    pub observations: Vec<PosteriorObservation>,
+    pub slices: Vec<PosteriorSlice>,
+}
+
+fn random_point(dim: usize, rng: &mut Prng) -> Vec<f64> {
+    (0..dim).map(|_| rng.random::<f64>()).collect()
+}
+
+// Greedy batch selection: after each pick, the posterior mean at the picked point is added
+// as a pretend observation ("kriging believer") so the rest of the batch looks elsewhere
+fn propose_batch(
+    config: &BayesianConfig,
+    xs: &[Vec<f64>],
+    ys: &[f64],
+    batch_size: usize,
+    rng: &mut Prng,
+) -> Vec<Vec<f64>> {
+    let dim = config.space.dim();
+    let best = ys.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let (mut xs, mut ys) = (xs.to_vec(), ys.to_vec());
+    let mut batch = Vec::new();
+    for _ in 0..batch_size {
+        let Some(gp) = GaussianProcess::fit(&xs, &ys, config.length_scale, config.noise) else {
+            batch.push(random_point(dim, rng));
+            continue;
+        };
+        let (x, _) = (0..config.num_proposals.max(1))
+            .map(|_| {
+                let x = random_point(dim, rng);
+                let (mean, std) = gp.predict(&x);
+                let score = config.acquisition.score(mean, std, best);
+                (x, score)
+            })
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .unwrap();
+        let (mean, _) = gp.predict(&x);
+        xs.push(x.clone());
+        ys.push(mean);
+        batch.push(x);
+    }
+    batch
+}
+
+fn posterior(config: &BayesianConfig, xs: &[Vec<f64>], ys: &[f64]) -> Option<Posterior> {
+    let gp = GaussianProcess::fit(xs, ys, config.length_scale, config.noise)?;
+    let (best_idx, best_fitness) = ys
+        .iter()
+        .cloned()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))?;
+    let best_x = &xs[best_idx];
+
+    let slices = config
+        .space
+        .names()
+        .into_iter()
+        .enumerate()
+        .map(|(dim, parameter)| {
+            let mut slice = PosteriorSlice {
+                parameter,
+                values: Vec::new(),
+                mean: Vec::new(),
+                std: Vec::new(),
+            };
+            for i in 0..SLICE_POINTS {
+                let mut x = best_x.clone();
+                x[dim] = i as f64 / (SLICE_POINTS - 1) as f64;
+                let (mean, std) = gp.predict(&x);
+                slice.values.push(config.space.scale(&x)[dim]);
+                slice.mean.push(mean);
+                slice.std.push(std);
+            }
+            slice
+        })
+        .collect();
+
+    Some(Posterior {
+        parameters: config.space.names(),
+        best_params: config.space.scale(best_x),
+        best_fitness,
+        observations: xs
+            .iter()
+            .zip(ys)
+            .map(|(x, fitness)| PosteriorObservation {
+                params: config.space.scale(x),
+                fitness: *fitness,
+            })
+            .collect(),
+        slices,
+    })
+}
+
+impl EvoEngine {
+    // Every generation is a batch of proposals, the first one drawn uniformly at random.
+    // The GP posterior is written to `{output_dir}/posterior.toml` at the end.
+    pub(crate) fn step_bayesian_and_save(
+        &mut self,
+        config: BayesianConfig,
+        output_dir: &str,
+        rng: &mut Prng,
+    ) -> Result<()> {
+        let batch_size = config.batch_size.unwrap_or_else(rayon::current_num_threads);
+        let dim = config.space.dim();
+        log::info!(
+            "Bayesian optimisation over {:?} in batches of {batch_size}\n",
+            config.space.names()
+        );
+        let mut xs: Vec<Vec<f64>> = Vec::new();
+        let mut ys: Vec<f64> = Vec::new();
+        // the observed protocols, checkpointed as a genepool
+        let mut observed: Vec<(Dna, f64, usize)> = Vec::new();
+
+        for gen_idx in 0..self.num_generations {
+            log::info!("Starting generation {gen_idx}: ");
+            let points = if xs.is_empty() {
+                (0..batch_size).map(|_| random_point(dim, rng)).collect()
+            } else {
+                propose_batch(&config, &xs, &ys, batch_size, rng)
+            };
+            let candidates: Vec<Dna> = points
+                .iter()
+                .map(|x| {
+                    let protocol = config.space.protocol(x, &self.init_protocol)?;
+                    let dna = Dna::fresh_fixed(protocol).with_id(self.child_ctr);
+                    self.child_ctr += 1;
+                    Ok(dna)
+                })
+                .collect::<Result<_>>()?;
+            for (dna, x) in candidates.iter().zip(points.iter()) {
+                log::info!("Candidate {}: {:?}", dna.id(), config.space.scale(x));
+            }
+
+            let start = Instant::now();
+            let gen_dir = format!("./{output_dir}/{:0>3}", gen_idx);
+            let children = self.step_generation_to(&candidates, self.fitness(), rng, &gen_dir);
+            log::info!("Generation execution time: {:?}", Instant::now() - start);
+
+            let fitnesses = self.get_fitnesses(&children);
+            self.record_hall_of_fame(&candidates, &fitnesses, gen_idx, &gen_dir, output_dir);
+            // failed children say nothing about the objective
+            for (idx, (x, child)) in points.into_iter().zip(&children).enumerate() {
+                if let (Some(fitness), Some(summary)) = (child.fitness(), &child.summary) {
+                    xs.push(x);
+                    ys.push(fitness);
+                    observed.push((candidates[idx].clone(), fitness, summary.polygons));
+                }
+            }
+            self.record_checkpoint(output_dir, gen_idx, &[observed.as_slice()]);
+
+            if self.should_stop(&fitnesses, megasteps_run(&children)) {
+                break;
+            }
+            if self.is_cancelled() {
+                log::info!("Run cancelled after generation {gen_idx}");
+                break;
+            }
+        }
+
+        match posterior(&config, &xs, &ys) {
+            Some(posterior) => {
+                log::info!(
+                    "Best parameters: {:?} (fitness = {})",
+                    posterior.best_params,
+                    posterior.best_fitness
+                );
+                let path = format!("{output_dir}/posterior.toml");
+                if let Err(err) = write_posterior_toml(&posterior, &path) {
+                    log::warn!("Unable to write posterior: {err:#}");
+                }
+            }
+            None => log::warn!("No successful children, not writing a posterior"),
+        }
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use l2g::distributed::run_worker;
 use l2g::engine::EvoEngine;
 use l2g::io::write_progress_png;
+use l2g::sweep::{run_sweep, SweepConfig};
 use log::LevelFilter;
 use log4rs::append::console::{ConsoleAppender, Target};
 use log4rs::append::file::FileAppender;
@@ -8,9 +10,48 @@ use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
 use rand::SeedableRng;
 use std::fs::{self, create_dir_all};
+use std::sync::atomic::Ordering;
 use vmmc::cli::VmmcConfig;
 use vmmc::Prng;
 
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    config: VmmcConfig,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Evaluate simulations sent by a coordinator running with a `distributed` config
+    Worker {
+        /// Coordinator address, `tcp:<host>:<port>` or `unix:<path>`
+        #[arg(long)]
+        connect: String,
+    },
+    /// Grid or Latin-hypercube baseline over flat protocols and simulation parameters
+    Sweep {
+        /// TOML file with the swept parameters, sampling and replicates
+        #[arg(long)]
+        spec: String,
+    },
+}
+
+fn init_console_logging() -> anyhow::Result<()> {
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{m}\n")))
+        .target(Target::Stdout)
+        .build();
+    let stdout_appender = Appender::builder().build("stdout", Box::new(stdout));
+    let config = Config::builder()
+        .appender(stdout_appender)
+        .build(Root::builder().appender("stdout").build(LevelFilter::Info))?;
+
+    log4rs::init_config(config)?;
+    Ok(())
+}
+
 fn init_logging(output_dir: &str) -> anyhow::Result<()> {
     // env_logger::init();
     // log::set_logger(&L2G_LOGGER)?;
@@ -42,10 +83,15 @@ fn init_logging(output_dir: &str) -> anyhow::Result<()> {
 
 fn main() -> anyhow::Result<()> {
     // Get commandline arguments
-    let config = VmmcConfig::parse();
+    let cli = Cli::parse();
+    if let Some(Command::Worker { connect }) = &cli.command {
+        init_console_logging()?;
+        return run_worker(connect);
+    }
+    let config = cli.config;
     init_logging(config.output_dir())?;
 
-    let mut engine = if config.input() != "" {
+    let mut engine: EvoEngine = if config.input() != "" {
         log::info!("Reading configuration from {}", config.input());
         let contents = fs::read_to_string(config.input())?;
         toml::from_str(&contents)?
@@ -53,6 +99,8 @@ fn main() -> anyhow::Result<()> {
         log::info!("No configuration provided, using default config");
         EvoEngine::default()
     };
+    engine.validate()?;
+    engine.prepare_fitness()?;
 
     // let init_dna = engine.init_dna();
 
@@ -69,8 +117,16 @@ fn main() -> anyhow::Result<()> {
         engine.children_per_survivor()
     );
     log::info!("Fitness Function: {:?}", engine.fitness_func);
+    log::info!("Optimizer: {:?}", engine.optimizer);
     log::info!("Mutation Method: {:?}", engine.learning_strategy);
     log::info!("Mutation factor: {:?}", engine.mutation_factor);
+    log::info!("Mutation schedule: {:?}", engine.mutation_schedule);
+    log::info!("Stopping criteria: {:?}", engine.stopping);
+    log::info!("Fault tolerance: {:?}", engine.fault_tolerance);
+    log::info!("Protocol penalty: {:?}", engine.protocol_penalty);
+    log::info!("Per-child budget: {:?}", engine.sim_budget);
+    log::info!("Multi-fidelity: {:?}", engine.multi_fidelity);
+    log::info!("Surrogate: {:?}", engine.surrogate);
     let ip = engine.sim_params();
     log::info!(
         "Simbox: {}x{} with {} initial particles",
@@ -88,7 +144,22 @@ fn main() -> anyhow::Result<()> {
     let toml = toml::to_string(&engine).unwrap();
     fs::write(config.toml(), toml).expect("Unable to write file");
 
-    engine.step_all_and_save(config.output_dir(), &mut rng);
+    // First interrupt finishes the current generation, second one exits immediately
+    let cancel = engine.cancel_handle();
+    ctrlc::set_handler(move || {
+        if cancel.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        log::warn!("Interrupted, finishing current generation (interrupt again to exit now)");
+    })?;
+
+    if let Some(Command::Sweep { spec }) = &cli.command {
+        log::info!("Reading sweep from {spec}");
+        let sweep: SweepConfig = toml::from_str(&fs::read_to_string(spec)?)?;
+        return run_sweep(&engine, &sweep, config.output_dir(), &mut rng);
+    }
+
+    engine.step_all_and_save(config.output_dir(), &mut rng)?;
 
     let fit_path = format!("{}/fitnesses.txt", config.output_dir());
     fs::write(fit_path, format!("{:?}", &engine.fitnesses)).expect("Unable to write file");
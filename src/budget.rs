@@ -0,0 +1,131 @@
+/// Per-simulation budgets and cancellation, enforced cooperatively from the protocol iterator
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use vmmc::protocol::{ProtocolIter, ProtocolStep};
+use vmmc::vmmc::Vmmc;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SimBudget {
+    #[serde(default)]
+    pub max_wall_time_secs: Option<u64>,
+    #[serde(default)]
+    pub max_megasteps: Option<usize>,
+    // ends the protocol after this many megasteps without failing the child, unlike
+    // `max_megasteps`; set per rung by multi-fidelity evaluation
+    #[serde(default)]
+    pub protocol_limit: Option<usize>,
+}
+
+/// Why a simulation was stopped before its protocol finished
+#[derive(Debug, Clone)]
+pub enum Interrupt {
+    TimedOut(Duration),
+    MegastepBudget(usize),
+    Cancelled,
+}
+
+impl fmt::Display for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupt::TimedOut(elapsed) => write!(f, "simulation timed out after {elapsed:?}"),
+            Interrupt::MegastepBudget(steps) => {
+                write!(f, "simulation exceeded its budget of {steps} megasteps")
+            }
+            Interrupt::Cancelled => write!(f, "simulation cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupt {}
+
+// Wraps a protocol and ends it early once the budget is spent or the run is cancelled.
+// The reason is left in `interrupt` so the caller can tell a truncated run from a finished one.
+// Every megastep handed to the simulation is added to `simulated`, whether or not it finishes.
+pub struct BudgetedIter {
+    inner: Box<dyn ProtocolIter>,
+    budget: SimBudget,
+    cancel: Arc<AtomicBool>,
+    start: Instant,
+    steps: usize,
+    interrupt: Arc<Mutex<Option<Interrupt>>>,
+    simulated: Arc<AtomicUsize>,
+}
+
+impl BudgetedIter {
+    pub fn new(
+        inner: Box<dyn ProtocolIter>,
+        budget: SimBudget,
+        cancel: Arc<AtomicBool>,
+        interrupt: Arc<Mutex<Option<Interrupt>>>,
+        simulated: Arc<AtomicUsize>,
+    ) -> Self {
+        Self {
+            inner,
+            budget,
+            cancel,
+            start: Instant::now(),
+            steps: 0,
+            interrupt,
+            simulated,
+        }
+    }
+
+    fn check(&self) -> Option<Interrupt> {
+        if self.cancel.load(Ordering::Relaxed) {
+            return Some(Interrupt::Cancelled);
+        }
+        if let Some(secs) = self.budget.max_wall_time_secs {
+            let elapsed = self.start.elapsed();
+            if elapsed > Duration::from_secs(secs) {
+                return Some(Interrupt::TimedOut(elapsed));
+            }
+        }
+        if let Some(max_megasteps) = self.budget.max_megasteps {
+            if self.steps >= max_megasteps {
+                return Some(Interrupt::MegastepBudget(max_megasteps));
+            }
+        }
+        None
+    }
+}
+
+impl ProtocolIter for BudgetedIter {
+    fn next(&mut self, vmmc: &Vmmc) -> Option<ProtocolStep> {
+        if self
+            .budget
+            .protocol_limit
+            .is_some_and(|limit| self.steps >= limit)
+        {
+            return None;
+        }
+        if let Some(interrupt) = self.check() {
+            *self.interrupt.lock().unwrap() = Some(interrupt);
+            return None;
+        }
+        self.steps += 1;
+        let step = self.inner.next(vmmc);
+        if step.is_some() {
+            self.simulated.fetch_add(1, Ordering::Relaxed);
+        }
+        step
+    }
+
+    fn peek(&self, vmmc: &Vmmc) -> ProtocolStep {
+        self.inner.peek(vmmc)
+    }
+
+    fn start(&self) -> ProtocolStep {
+        self.inner.start()
+    }
+
+    fn len(&self) -> usize {
+        match self.budget.protocol_limit {
+            Some(limit) => self.inner.len().min(limit),
+            None => self.inner.len(),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+/// On-disk, content-addressed cache of child evaluations
+use std::path::PathBuf;
+
+use anyhow::Result;
+use serde::Serialize;
+use vmmc::SimParams;
+
+use vmmc::protocol::SynthesisProtocol;
+
+use crate::archive::Descriptor;
+use crate::distributed::Summary;
+use crate::engine::{EvoEngine, Optimizer};
+use crate::fitness::{FitnessFunc, ProtocolPenalty};
+use crate::nn::Dna;
+
+// 64-bit FNV-1a, stable across platforms and compiler versions unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[derive(Serialize)]
+struct CacheKey<'a> {
+    sim_params: &'a SimParams,
+    genome: &'a str,
+    fitness_func: &'a FitnessFunc,
+    protocol_penalty: &'a ProtocolPenalty,
+    init_protocol: &'a SynthesisProtocol,
+    protocol_limit: Option<usize>,
+    // archive descriptors stored in the summary, absent outside MAP-Elites so those keys
+    // are shared between optimizers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    descriptors: Option<(&'a Descriptor, &'a Descriptor)>,
+    seed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FitnessCache {
+    dir: PathBuf,
+}
+
+impl FitnessCache {
+    pub fn new(output_dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(format!("{output_dir}/cache")),
+        }
+    }
+
+    // `None` for evaluations that are not reproducible from their inputs
+    pub fn key(
+        engine: &EvoEngine,
+        dna: &Dna,
+        fitness_func: &FitnessFunc,
+        seed: u64,
+    ) -> Option<u64> {
+        if !fitness_func.is_deterministic() {
+            return None;
+        }
+        let genome = dna.genome_json().ok()?;
+        let descriptors = match &engine.optimizer {
+            Optimizer::MapElites(config) => Some((&config.x.descriptor, &config.y.descriptor)),
+            _ => None,
+        };
+        let key = CacheKey {
+            sim_params: engine.sim_params(),
+            genome: &genome,
+            fitness_func,
+            protocol_penalty: &engine.protocol_penalty,
+            init_protocol: &engine.init_protocol,
+            protocol_limit: engine.sim_budget.protocol_limit,
+            descriptors,
+            seed,
+        };
+        let bytes = serde_json::to_vec(&key).ok()?;
+        Some(fnv1a(&bytes))
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{key:016x}.toml"))
+    }
+
+    pub fn get(&self, key: u64) -> Option<Summary> {
+        let contents = std::fs::read_to_string(self.path(key)).ok()?;
+        toml::from_str(&contents).ok()
+    }
+
+    pub fn insert(&self, key: u64, summary: &Summary) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.path(key), toml::to_string(summary)?)?;
+        Ok(())
+    }
+}
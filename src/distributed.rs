@@ -0,0 +1,529 @@
+/// Coordinator/worker evaluation over TCP or Unix sockets.
+///
+/// Workers dial in to the coordinator and are sent one job at a time as a line of JSON.
+/// Each job is a full simulation; the worker answers with the fitness and a small summary
+/// of the final state, so nothing but the summary needs to cross the network.
+/// While a job runs the coordinator may send a cancel request, which stops the simulation.
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use vmmc::polygons::calc_polygon_count;
+use vmmc::protocol::SynthesisProtocol;
+use vmmc::vmmc::Vmmc;
+use vmmc::{Prng, SimParams};
+
+use crate::budget::{Interrupt, SimBudget};
+use crate::engine::panic_message;
+use crate::fitness::{FitnessFunc, PreparedFitness, ProtocolPenalty};
+use crate::nn::Dna;
+use crate::run_fresh_vmmc_with_budget;
+use crate::structure::cluster_sizes;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DistributedConfig {
+    // `tcp:<host>:<port>` or `unix:<path>`, a bare `<host>:<port>` is treated as tcp
+    pub listen: String,
+    // workers to wait for before the first generation, more may join later
+    pub min_workers: usize,
+    // a worker that does not answer a job within this time is dropped and its job handed
+    // to another worker
+    #[serde(default = "default_reply_timeout_secs")]
+    pub reply_timeout_secs: u64,
+}
+
+fn default_reply_timeout_secs() -> u64 {
+    3600
+}
+
+// how often the coordinator checks for a cancelled run while jobs are out
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub sim_params: SimParams,
+    pub dna: Dna,
+    pub seed: u64,
+    pub fitness_func: FitnessFunc,
+    pub sim_budget: SimBudget,
+    pub protocol_penalty: ProtocolPenalty,
+    pub init_protocol: SynthesisProtocol,
+    // failed simulations are retried on the worker with seeds derived from `seed`
+    pub max_retries: usize,
+}
+
+// Coordinator to worker
+#[derive(Serialize, Deserialize)]
+enum Request {
+    Evaluate(Box<Job>),
+    // stop the job that is currently running
+    Cancel,
+}
+
+/// Worker to coordinator. `megasteps` counts every attempt the worker simulated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Reply {
+    Done { summary: Summary, megasteps: usize },
+    Failed { error: String, megasteps: usize },
+    Cancelled { megasteps: usize },
+}
+
+/// What a worker reports back about a finished simulation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Summary {
+    pub fitness: f64,
+    pub polygons: usize,
+    pub num_particles: usize,
+    #[serde(default)]
+    pub largest_cluster: usize,
+    #[serde(default)]
+    pub num_clusters: usize,
+    // MAP-Elites descriptor values, so cached children can be archived
+    #[serde(default)]
+    pub descriptors: Option<(f64, f64)>,
+    pub megasteps: usize,
+    pub elapsed_secs: f64,
+}
+
+impl Summary {
+    pub fn new(fitness: f64, vmmc: &Vmmc, megasteps: usize, elapsed: Duration) -> Self {
+        let clusters = cluster_sizes(vmmc);
+        Self {
+            fitness,
+            polygons: calc_polygon_count(vmmc, 12),
+            num_particles: vmmc.particles().iter().count(),
+            largest_cluster: clusters.first().copied().unwrap_or(0),
+            num_clusters: clusters.len(),
+            descriptors: None,
+            megasteps,
+            elapsed_secs: elapsed.as_secs_f64(),
+        }
+    }
+}
+
+type Reader = BufReader<Box<dyn Read + Send>>;
+// shared so that a cancel request can be sent while a job is out
+type Writer = Arc<Mutex<Box<dyn Write + Send>>>;
+
+struct Connection {
+    peer: String,
+    reader: Reader,
+    writer: Writer,
+}
+
+impl Connection {
+    // `timeout` bounds every read and write, `None` blocks forever
+    fn from_tcp(stream: TcpStream, timeout: Option<Duration>) -> Result<Self> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        let peer = stream.peer_addr()?.to_string();
+        let reader: Box<dyn Read + Send> = Box::new(stream.try_clone()?);
+        Ok(Self {
+            peer,
+            reader: BufReader::new(reader),
+            writer: Arc::new(Mutex::new(Box::new(stream))),
+        })
+    }
+
+    #[cfg(unix)]
+    fn from_unix(stream: UnixStream, peer: String, timeout: Option<Duration>) -> Result<Self> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        let reader: Box<dyn Read + Send> = Box::new(stream.try_clone()?);
+        Ok(Self {
+            peer,
+            reader: BufReader::new(reader),
+            writer: Arc::new(Mutex::new(Box::new(stream))),
+        })
+    }
+
+    // Workers wait for jobs for as long as the coordinator is up, so no timeout here
+    fn connect(address: &str) -> Result<Self> {
+        match parse_address(address) {
+            Address::Tcp(addr) => Self::from_tcp(TcpStream::connect(addr)?, None),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                Self::from_unix(UnixStream::connect(path)?, path.to_string(), None)
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+        }
+    }
+
+    fn call(&mut self, job: &Job) -> Result<Reply> {
+        send(&self.writer, &Request::Evaluate(Box::new(job.clone())))?;
+        recv(&mut self.reader)?.ok_or_else(|| anyhow!("connection closed by worker"))
+    }
+}
+
+fn send<T: Serialize>(writer: &Writer, msg: &T) -> Result<()> {
+    let mut line = serde_json::to_string(msg)?;
+    line.push('\n');
+    let mut writer = writer.lock().unwrap();
+    writer.write_all(line.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+// `None` once the other end has hung up
+fn recv<T: DeserializeOwned>(reader: &mut Reader) -> Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+enum Address<'a> {
+    Tcp(&'a str),
+    Unix(&'a str),
+}
+
+fn parse_address(address: &str) -> Address<'_> {
+    if let Some(path) = address.strip_prefix("unix:") {
+        Address::Unix(path)
+    } else {
+        Address::Tcp(address.strip_prefix("tcp:").unwrap_or(address))
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    fn bind(address: &str) -> Result<Self> {
+        match parse_address(address) {
+            Address::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                // clear out a socket left behind by a previous run
+                let _ = std::fs::remove_file(path);
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.to_string()))
+            }
+            #[cfg(not(unix))]
+            Address::Unix(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            Listener::Tcp(l) => l.set_nonblocking(nonblocking)?,
+            #[cfg(unix)]
+            Listener::Unix(l, _) => l.set_nonblocking(nonblocking)?,
+        }
+        Ok(())
+    }
+
+    fn accept(&self, timeout: Option<Duration>) -> std::io::Result<Result<Connection>> {
+        match self {
+            Listener::Tcp(l) => l
+                .accept()
+                .map(|(stream, _)| Connection::from_tcp(stream, timeout)),
+            #[cfg(unix)]
+            Listener::Unix(l, path) => l
+                .accept()
+                .map(|(stream, _)| Connection::from_unix(stream, path.clone(), timeout)),
+        }
+    }
+}
+
+pub struct WorkerPool {
+    listener: Listener,
+    workers: Vec<Connection>,
+    timeout: Duration,
+}
+
+impl WorkerPool {
+    pub fn bind(config: &DistributedConfig) -> Result<Self> {
+        let listener = Listener::bind(&config.listen)?;
+        log::info!(
+            "Listening for workers on {}, waiting for {}",
+            config.listen,
+            config.min_workers
+        );
+        let mut pool = Self {
+            listener,
+            workers: Vec::new(),
+            timeout: Duration::from_secs(config.reply_timeout_secs),
+        };
+        while pool.workers.len() < config.min_workers {
+            pool.add_worker(pool.listener.accept(Some(pool.timeout))?);
+        }
+        Ok(pool)
+    }
+
+    pub fn num_workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    fn add_worker(&mut self, conn: Result<Connection>) {
+        match conn {
+            Ok(conn) => {
+                log::info!("Worker connected from {}", conn.peer);
+                self.workers.push(conn);
+            }
+            Err(err) => log::warn!("Failed to set up worker connection: {err:#}"),
+        }
+    }
+
+    // Pick up any workers that connected since the last generation
+    pub fn accept_pending(&mut self) -> Result<()> {
+        self.listener.set_nonblocking(true)?;
+        loop {
+            match self.listener.accept(Some(self.timeout)) {
+                Ok(conn) => self.add_worker(conn),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    self.listener.set_nonblocking(false)?;
+                    return Err(err.into());
+                }
+            }
+        }
+        self.listener.set_nonblocking(false)?;
+        Ok(())
+    }
+
+    // Evaluate all jobs on the connected workers. Jobs of a worker that disconnects or times
+    // out are handed to the others. Once `cancel` is set no new jobs go out, running jobs are
+    // cancelled on their workers and every unfinished job comes back as `Reply::Cancelled`.
+    pub fn evaluate(&mut self, jobs: Vec<Job>, cancel: &AtomicBool) -> Vec<Reply> {
+        let num_jobs = jobs.len();
+        let queue = Mutex::new(jobs.into_iter().enumerate().collect::<VecDeque<_>>());
+        let results: Mutex<Vec<Option<Reply>>> = Mutex::new(vec![None; num_jobs]);
+        let workers: Vec<Connection> = self.workers.drain(..).collect();
+        let writers: Vec<Writer> = workers.iter().map(|conn| conn.writer.clone()).collect();
+        let running = AtomicUsize::new(workers.len());
+        let (queue, results, running) = (&queue, &results, &running);
+
+        self.workers = thread::scope(|s| {
+            let handles: Vec<_> = workers
+                .into_iter()
+                .map(|conn| {
+                    s.spawn(move || {
+                        let conn = serve_jobs(conn, queue, results, cancel);
+                        running.fetch_sub(1, Ordering::Relaxed);
+                        conn
+                    })
+                })
+                .collect();
+            s.spawn(move || {
+                while running.load(Ordering::Relaxed) > 0 {
+                    if cancel.load(Ordering::Relaxed) {
+                        log::info!("Cancelling jobs on {} workers", writers.len());
+                        for writer in &writers {
+                            // a worker that is gone is already handled by its job thread
+                            let _ = send(writer, &Request::Cancel);
+                        }
+                        return;
+                    }
+                    thread::sleep(CANCEL_POLL);
+                }
+            });
+            handles
+                .into_iter()
+                .filter_map(|h| h.join().unwrap())
+                .collect()
+        });
+
+        let cancelled = cancel.load(Ordering::Relaxed);
+        let unfinished = queue.lock().unwrap().len();
+        if unfinished > 0 && !cancelled {
+            log::error!("No workers left, {unfinished} children were not evaluated");
+        }
+        std::mem::take(&mut *results.lock().unwrap())
+            .into_iter()
+            .map(|reply| match reply {
+                Some(reply) => reply,
+                None if cancelled => Reply::Cancelled { megasteps: 0 },
+                None => Reply::Failed {
+                    error: "no workers left to evaluate the child".to_string(),
+                    megasteps: 0,
+                },
+            })
+            .collect()
+    }
+}
+
+// Hand jobs to one worker until the queue is empty or the run is cancelled. Returns the
+// connection if the worker is still usable.
+fn serve_jobs(
+    mut conn: Connection,
+    queue: &Mutex<VecDeque<(usize, Job)>>,
+    results: &Mutex<Vec<Option<Reply>>>,
+    cancel: &AtomicBool,
+) -> Option<Connection> {
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Some(conn);
+        }
+        let Some((idx, job)) = queue.lock().unwrap().pop_front() else {
+            return Some(conn);
+        };
+        match conn.call(&job) {
+            Ok(reply) => {
+                if let Reply::Failed { error, .. } = &reply {
+                    log::warn!(
+                        "Child {} failed on worker {}: {error}",
+                        job.dna.id(),
+                        conn.peer
+                    );
+                }
+                results.lock().unwrap()[idx] = Some(reply);
+            }
+            Err(err) => {
+                log::warn!("Lost worker {}: {err:#}", conn.peer);
+                queue.lock().unwrap().push_back((idx, job));
+                return None;
+            }
+        }
+    }
+}
+
+// Prepared fitness functions by job config, so a worker parses tilings and references once
+#[derive(Default)]
+struct PreparedCache {
+    prepared: HashMap<String, PreparedFitness>,
+}
+
+impl PreparedCache {
+    fn get(&mut self, job: &Job) -> Result<&PreparedFitness> {
+        let key = serde_json::to_string(&(&job.fitness_func, &job.sim_params))?;
+        if !self.prepared.contains_key(&key) {
+            let prepared = job.fitness_func.prepare(&job.sim_params)?;
+            self.prepared.insert(key.clone(), prepared);
+        }
+        Ok(&self.prepared[&key])
+    }
+}
+
+// Run a job, retrying failures like `EvoEngine::run_child` does locally
+fn evaluate_job(job: &Job, fitness_func: &PreparedFitness, cancel: &Arc<AtomicBool>) -> Reply {
+    let mut rng = Prng::seed_from_u64(job.seed);
+    let simulated = Arc::new(AtomicUsize::new(0));
+    let spent = || simulated.load(Ordering::Relaxed);
+    let mut last_err = String::new();
+    for attempt in 0..=job.max_retries {
+        match try_job(job, fitness_func, cancel, &simulated, &mut rng) {
+            Ok(summary) => {
+                return Reply::Done {
+                    summary,
+                    megasteps: spent(),
+                }
+            }
+            Err(err) => {
+                log::warn!(
+                    "Child {} failed on attempt {}: {err:#}",
+                    job.dna.id(),
+                    attempt + 1
+                );
+                if matches!(err.downcast_ref::<Interrupt>(), Some(Interrupt::Cancelled)) {
+                    return Reply::Cancelled { megasteps: spent() };
+                }
+                last_err = format!("{err:#}");
+                rng = Prng::seed_from_u64(rng.random());
+            }
+        }
+    }
+    Reply::Failed {
+        error: last_err,
+        megasteps: spent(),
+    }
+}
+
+fn try_job(
+    job: &Job,
+    fitness_func: &PreparedFitness,
+    cancel: &Arc<AtomicBool>,
+    simulated: &Arc<AtomicUsize>,
+    rng: &mut Prng,
+) -> Result<Summary> {
+    let start = Instant::now();
+    let (protocol_iter, trajectory) = fitness_func.observe(job.dna.protocol_iter(), rng);
+    let (proto, vmmc) = catch_unwind(AssertUnwindSafe(|| {
+        run_fresh_vmmc_with_budget(
+            &job.sim_params,
+            protocol_iter,
+            &job.sim_budget,
+            cancel.clone(),
+            simulated.clone(),
+            rng,
+        )
+    }))
+    .unwrap_or_else(|payload| Err(anyhow!("Simulation panicked: {}", panic_message(&*payload))))?;
+    let fitness = fitness_func.score(
+        &vmmc,
+        &trajectory,
+        &proto,
+        &job.protocol_penalty,
+        &job.init_protocol,
+        rng,
+    );
+    Ok(Summary::new(fitness, &vmmc, proto.len(), start.elapsed()))
+}
+
+/// Connect to a coordinator and evaluate jobs until it hangs up
+pub fn run_worker(address: &str) -> Result<()> {
+    let Connection {
+        mut reader, writer, ..
+    } = Connection::connect(address)?;
+    log::info!("Connected to coordinator at {address}");
+    let cancel = Arc::new(AtomicBool::new(false));
+
+    // requests are read on their own thread so a cancel reaches the running simulation
+    let (jobs, queued) = mpsc::channel::<Box<Job>>();
+    let reader_cancel = cancel.clone();
+    let reader_thread = thread::spawn(move || -> Result<()> {
+        while let Some(request) = recv::<Request>(&mut reader)? {
+            match request {
+                Request::Evaluate(job) => {
+                    // a cancel only applies to the job that was running when it was sent
+                    reader_cancel.store(false, Ordering::Relaxed);
+                    if jobs.send(job).is_err() {
+                        break;
+                    }
+                }
+                Request::Cancel => reader_cancel.store(true, Ordering::Relaxed),
+            }
+        }
+        Ok(())
+    });
+
+    let mut prepared = PreparedCache::default();
+    for job in queued {
+        log::info!("Evaluating child {}", job.dna.id());
+        let reply = match prepared.get(&job) {
+            Ok(fitness_func) => evaluate_job(&job, fitness_func, &cancel),
+            Err(err) => Reply::Failed {
+                error: format!("{err:#}"),
+                megasteps: 0,
+            },
+        };
+        match &reply {
+            Reply::Done { summary, .. } => {
+                log::info!("Child {} fitness = {}", job.dna.id(), summary.fitness)
+            }
+            Reply::Failed { error, .. } => log::warn!("Child {} failed: {error}", job.dna.id()),
+            Reply::Cancelled { .. } => log::info!("Child {} cancelled", job.dna.id()),
+        }
+        send(&writer, &reply)?;
+    }
+    reader_thread.join().unwrap()?;
+    log::info!("Coordinator closed the connection");
+    Ok(())
+}
@@ -1,16 +1,35 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Instant;
 
-use crate::fitness::FitnessFunc;
-use crate::io::{record_child, record_child_config};
+use crate::archive::MapElitesConfig;
+use crate::bayesian::BayesianConfig;
+use crate::budget::{Interrupt, SimBudget};
+use crate::cache::FitnessCache;
+use crate::distributed::{DistributedConfig, Job, Reply, Summary, WorkerPool};
+use crate::fidelity::MultiFidelityConfig;
+use crate::fitness::{FitnessFunc, PreparedFitness, ProtocolPenalty, Trajectory};
+use crate::hall_of_fame::HallOfFame;
+use crate::io::{
+    record_child, record_child_config, record_child_error, record_particle_labels, record_summary,
+    write_checkpoint, write_hall_of_fame_toml,
+};
+use crate::islands::IslandConfig;
+use crate::local_search::{AnnealingConfig, HillClimbConfig};
 use crate::nn::{Dna, LearningStrategy};
-use crate::pruning::prune;
-use crate::run_fresh_vmmc;
-use anyhow::Result;
+use crate::policy_gradient::PolicyGradientConfig;
+use crate::pruning::{elites, prune};
+use crate::run_fresh_vmmc_with_budget;
+use crate::schedule::{MutationSchedule, ScheduleState};
+use crate::steady_state::SteadyStateConfig;
+use crate::stopping::{StoppingCriteria, StoppingState};
+use crate::surrogate::{Observations, Surrogate, SurrogateConfig};
+use anyhow::{anyhow, bail, Result};
 use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use vmmc::polygons::calc_polygon_count;
-use vmmc::protocol::{ProtocolStep, SynthesisProtocol};
+use vmmc::protocol::{ProtocolIter, ProtocolStep, SynthesisProtocol};
 use vmmc::vmmc::Vmmc;
 use vmmc::Prng;
 use vmmc::SimParams;
@@ -22,6 +41,104 @@ fn default_mutation_factor() -> f32 {
     0.2
 }
 
+fn default_hall_of_fame_size() -> usize {
+    10
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FaultTolerance {
+    // fitness logged and plotted for children whose simulation failed, they are never selected
+    #[serde(default)]
+    pub penalty_fitness: f64,
+    // number of times a failed child is re-run with a fresh seed
+    #[serde(default)]
+    pub max_retries: usize,
+}
+
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Outcome of evaluating one child
+pub struct Evaluation {
+    // `None` if the simulation failed
+    pub summary: Option<Summary>,
+    // final state, only available for children simulated in this process
+    pub vmmc: Option<Vmmc>,
+    // interrupted by a cancellation rather than failed, the child was never really evaluated
+    pub cancelled: bool,
+    // screened out on a reduced-fidelity rung, its summary is not comparable to full runs
+    pub eliminated: bool,
+    // megasteps simulated for this child across all attempts and rungs, 0 if served from cache
+    pub megasteps: usize,
+}
+
+impl Evaluation {
+    pub(crate) fn new(summary: Option<Summary>, vmmc: Option<Vmmc>) -> Self {
+        Self {
+            summary,
+            vmmc,
+            cancelled: false,
+            eliminated: false,
+            megasteps: 0,
+        }
+    }
+
+    pub(crate) fn with_megasteps(mut self, megasteps: usize) -> Self {
+        self.megasteps = megasteps;
+        self
+    }
+
+    pub(crate) fn failed() -> Self {
+        Self::new(None, None)
+    }
+
+    pub(crate) fn cancelled() -> Self {
+        Self {
+            cancelled: true,
+            ..Self::failed()
+        }
+    }
+
+    // Fitness the child can be selected on, `None` if it failed, was cancelled or eliminated
+    pub fn fitness(&self) -> Option<f64> {
+        match &self.summary {
+            Some(summary) if !self.eliminated => Some(summary.fitness),
+            _ => None,
+        }
+    }
+}
+
+// Simulation cost of a batch of children, counted towards the stopping budget
+pub(crate) fn megasteps_run(children: &[Evaluation]) -> usize {
+    children.iter().map(|c| c.megasteps).sum()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Optimizer {
+    #[default]
+    Genetic,
+    // quality-diversity search over an archive of behaviour descriptors
+    MapElites(MapElitesConfig),
+    // independent genepools with periodic migration between them
+    Islands(IslandConfig),
+    // asynchronous evolution without generations, a new child is spawned whenever one finishes
+    SteadyState(SteadyStateConfig),
+    // GP-guided batches of explicit protocols drawn from a low-dimensional search space
+    Bayesian(BayesianConfig),
+    // single-trajectory baselines on the same genomes
+    HillClimbing(HillClimbConfig),
+    Annealing(AnnealingConfig),
+    // REINFORCE training of the Microstate controller
+    PolicyGradient(PolicyGradientConfig),
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct EvoEngine {
     pub seed: u32,
@@ -31,13 +148,45 @@ pub struct EvoEngine {
     // pub init_dna: Dna,
     pub learning_strategy: LearningStrategy,
     pub fitness_func: FitnessFunc,
+    #[serde(default)]
+    pub optimizer: Optimizer,
 
     pub num_generations: usize,
     pub survivors_per_generation: usize,
     pub children_per_survivor: usize,
+    // number of fittest survivors carried unmutated into the next generation
+    #[serde(default)]
+    pub elitism: usize,
+    #[serde(default = "default_hall_of_fame_size")]
+    pub hall_of_fame_size: usize,
 
     #[serde(default = "default_mutation_factor")]
     pub mutation_factor: f32,
+    #[serde(default)]
+    pub mutation_schedule: MutationSchedule,
+    #[serde(default)]
+    pub stopping: StoppingCriteria,
+    #[serde(default)]
+    pub fault_tolerance: FaultTolerance,
+    // subtracted from every child's fitness to favour smooth, realistic protocols
+    #[serde(default)]
+    pub protocol_penalty: ProtocolPenalty,
+    // per-child limits, children that exceed them count as failed
+    #[serde(default)]
+    pub sim_budget: SimBudget,
+    // evaluate children on remote `l2g worker` processes instead of the local threadpool
+    #[serde(default)]
+    pub distributed: Option<DistributedConfig>,
+    // reuse results of children already evaluated with the same genome and seed,
+    // stored in `{output_dir}/cache`
+    #[serde(default)]
+    pub cache_fitness: bool,
+    // screen candidates on cheap truncated or shrunken runs before full-length evaluation
+    #[serde(default)]
+    pub multi_fidelity: Option<MultiFidelityConfig>,
+    // pre-screen oversampled mutants with a surrogate model of the fitness
+    #[serde(default)]
+    pub surrogate: Option<SurrogateConfig>,
 
     // runtime state
     #[serde(default)]
@@ -49,6 +198,19 @@ pub struct EvoEngine {
     #[serde(default)]
     #[serde(skip_serializing)]
     pub fitnesses: Vec<f64>,
+    #[serde(skip)]
+    pub schedule_state: ScheduleState,
+    #[serde(skip)]
+    pub hall_of_fame: HallOfFame,
+    #[serde(skip)]
+    pub stopping_state: StoppingState,
+    #[serde(skip)]
+    pub cancel: Arc<AtomicBool>,
+    #[serde(skip)]
+    pub cache: Option<FitnessCache>,
+    // `fitness_func` with its tilings and targets parsed, set by `prepare_fitness`
+    #[serde(skip)]
+    pub prepared_fitness: Option<PreparedFitness>,
 }
 
 impl EvoEngine {
@@ -80,13 +242,18 @@ impl EvoEngine {
         polygons: Vec<usize>,
     ) {
         prune(
-            genepool, candidates, fitnesses, polygons,
+            genepool,
+            candidates,
+            fitnesses,
+            polygons,
+            self.elitism,
             // &self.fitness_func,
         );
     }
 
-    fn mutate(&mut self, dna: &mut Dna) {
+    pub(crate) fn mutate(&mut self, dna: &mut Dna, mutation_factor: f32) {
         // use DnaInner::*;
+        dna.set_mutation_factor(mutation_factor);
         dna.mutate(self.child_ctr);
         self.child_ctr += 1;
     }
@@ -96,78 +263,489 @@ impl EvoEngine {
         self.spawn_children(&[(init_dna.clone(), 0., 0)], self.generation_size())
     }
 
-    fn step_one(&self, dna: &Dna, rng: &mut Prng) -> Result<(Vec<ProtocolStep>, Vmmc)> {
-        let protocol_iter = dna.protocol_iter();
-        run_fresh_vmmc(self.sim_params(), protocol_iter, rng)
+    // Flag checked by every running simulation, set it to wind the run down
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        self.cancel.clone()
     }
 
-    fn step_generation_to(
-        &mut self,
+    // Reject optimizer settings that would only fail, or panic, in the middle of a run
+    pub fn validate(&self) -> Result<()> {
+        match &self.optimizer {
+            Optimizer::MapElites(config) => config.validate(&self.sim_params)?,
+            Optimizer::Islands(config) => config.validate()?,
+            Optimizer::Bayesian(config) => config.validate()?,
+            _ => {}
+        }
+        self.validate_surrogate()?;
+        // the fidelity ladder lives in `step_generation_to`, which these optimizers bypass
+        if self.multi_fidelity.is_some() {
+            match self.optimizer {
+                Optimizer::SteadyState(_) => {
+                    bail!("multi_fidelity is not supported by the SteadyState optimizer")
+                }
+                Optimizer::PolicyGradient(_) => {
+                    bail!("multi_fidelity is not supported by the PolicyGradient optimizer")
+                }
+                _ => {}
+            }
+        }
+        if self.distributed.is_some() {
+            if !matches!(self.optimizer, Optimizer::Genetic) {
+                bail!("distributed evaluation is only supported by the Genetic optimizer");
+            }
+            if self.multi_fidelity.is_some() {
+                bail!("distributed evaluation does not support multi_fidelity");
+            }
+        }
+        Ok(())
+    }
+
+    // Check the fitness function against the simulation and parse its inputs, so that a bad
+    // config fails before the first simulation instead of after it
+    pub fn prepare_fitness(&mut self) -> Result<()> {
+        self.prepared_fitness = Some(self.fitness_func.prepare(&self.sim_params)?);
+        Ok(())
+    }
+
+    pub(crate) fn fitness(&self) -> &PreparedFitness {
+        self.prepared_fitness
+            .as_ref()
+            .expect("prepare_fitness runs before any simulation")
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    fn step_one(
+        &self,
+        protocol_iter: Box<dyn ProtocolIter>,
+        fitness_func: &PreparedFitness,
+        simulated: &Arc<AtomicUsize>,
+        rng: &mut Prng,
+    ) -> Result<(Vec<ProtocolStep>, Vmmc, Trajectory)> {
+        let (protocol_iter, trajectory) = fitness_func.observe(protocol_iter, rng);
+        let (proto, vmmc) = run_fresh_vmmc_with_budget(
+            self.sim_params(),
+            protocol_iter,
+            &self.sim_budget,
+            self.cancel_handle(),
+            simulated.clone(),
+            rng,
+        )?;
+        Ok((proto, vmmc, trajectory))
+    }
+
+    // Simulate, score and record one child, turning panics into errors
+    fn try_child(
+        &self,
+        dna: &Dna,
+        protocol_iter: Box<dyn ProtocolIter>,
+        fitness_func: &PreparedFitness,
+        simulated: &Arc<AtomicUsize>,
+        rng: &mut Prng,
+        p_str: &str,
+    ) -> Result<(Summary, Vmmc)> {
+        catch_unwind(AssertUnwindSafe(|| -> Result<(Summary, Vmmc)> {
+            let start = Instant::now();
+            record_child_config(p_str, dna)?;
+            let (proto, child, trajectory) =
+                self.step_one(protocol_iter, fitness_func, simulated, rng)?;
+            let fitness = fitness_func.score(
+                &child,
+                &trajectory,
+                &proto,
+                &self.protocol_penalty,
+                &self.init_protocol,
+                rng,
+            );
+            let mut summary = Summary::new(fitness, &child, proto.len(), start.elapsed());
+            if let Optimizer::MapElites(config) = &self.optimizer {
+                summary.descriptors = Some(config.describe(&child));
+            }
+            record_child(p_str, &child, proto)?;
+            record_particle_labels(p_str, &child, fitness_func)?;
+            Ok((summary, child))
+        }))
+        .unwrap_or_else(|payload| Err(anyhow!("Simulation panicked: {}", panic_message(&*payload))))
+    }
+
+    // Failed children come back without a summary once their retries are exhausted
+    pub(crate) fn step_generation_to(
+        &self,
         states: &[Dna],
+        fitness_func: &PreparedFitness,
         rng: &mut Prng,
         output_dir: &str,
-    ) -> Vec<Vmmc> {
+    ) -> Vec<Evaluation> {
+        if let Some(config) = &self.multi_fidelity {
+            return self.step_generation_multi_fidelity(
+                config,
+                states,
+                fitness_func,
+                rng,
+                output_dir,
+            );
+        }
         let seeds: Vec<u64> = (0..states.len()).map(|_| rng.random()).collect();
         states
             .par_iter()
             .enumerate()
             .map(|(idx, s)| {
-                let thread_seed = seeds[idx];
-                let mut thread_rng = Prng::seed_from_u64(thread_seed);
                 let p_str = format!("./{output_dir}/{:0>3}", idx);
-
-                record_child_config(&p_str, s);
-                let (proto, child) = self
-                    .step_one(s, &mut thread_rng)
-                    .expect("Simulation failed");
-                record_child(&p_str, &child, proto);
-                child
+                self.run_child(s, fitness_func, seeds[idx], &p_str)
             })
             .collect()
     }
 
-    fn get_fitnesses(&mut self, children: &[Vmmc], rng: &mut Prng) -> Vec<f64> {
-        let fitnesses: Vec<f64> = children
+    // Look up a child that was already evaluated with the same genome and seed
+    fn cached_child(&self, key: Option<u64>, dna: &Dna, p_str: &str) -> Option<Summary> {
+        let summary = self.cache.as_ref()?.get(key?)?;
+        log::info!("Child {} served from cache", dna.id());
+        let res = record_child_config(p_str, dna).and_then(|_| record_summary(p_str, &summary));
+        if let Err(err) = res {
+            log::warn!("Unable to record child {}: {err:#}", dna.id());
+        }
+        Some(summary)
+    }
+
+    fn cache_child(&self, key: Option<u64>, dna: &Dna, summary: &Summary) {
+        if let (Some(cache), Some(key)) = (&self.cache, key) {
+            if let Err(err) = cache.insert(key, summary) {
+                log::warn!("Unable to cache child {}: {err:#}", dna.id());
+            }
+        }
+    }
+
+    // Simulate and record one child into `p_str`, retrying with fresh seeds on failure.
+    // Retry seeds are derived from `seed`, so the outcome is cached under the original seed.
+    pub(crate) fn run_child(
+        &self,
+        dna: &Dna,
+        fitness_func: &PreparedFitness,
+        seed: u64,
+        p_str: &str,
+    ) -> Evaluation {
+        let key = self
+            .cache
+            .as_ref()
+            .and_then(|_| FitnessCache::key(self, dna, fitness_func.func(), seed));
+        if let Some(summary) = self.cached_child(key, dna, p_str) {
+            return Evaluation::new(Some(summary), None);
+        }
+        let evaluation = self.run_attempts(dna, fitness_func, seed, p_str, |_| dna.protocol_iter());
+        if let Some(summary) = &evaluation.summary {
+            self.cache_child(key, dna, summary);
+        }
+        evaluation
+    }
+
+    // Simulate `dna` with the protocol `protocol_iter` builds for every attempt, up to
+    // `max_retries` times with seeds derived from `seed`. Bypasses the cache. The megasteps of
+    // failed attempts count towards the evaluation's cost.
+    pub(crate) fn run_attempts(
+        &self,
+        dna: &Dna,
+        fitness_func: &PreparedFitness,
+        seed: u64,
+        p_str: &str,
+        protocol_iter: impl Fn(&mut Prng) -> Box<dyn ProtocolIter>,
+    ) -> Evaluation {
+        let mut thread_rng = Prng::seed_from_u64(seed);
+        let simulated = Arc::new(AtomicUsize::new(0));
+        let spent = || simulated.load(Ordering::Relaxed);
+        for attempt in 0..=self.fault_tolerance.max_retries {
+            let proto_iter = protocol_iter(&mut thread_rng);
+            let res = self.try_child(
+                dna,
+                proto_iter,
+                fitness_func,
+                &simulated,
+                &mut thread_rng,
+                p_str,
+            );
+            match res {
+                Ok((summary, child)) => {
+                    return Evaluation::new(Some(summary), Some(child)).with_megasteps(spent());
+                }
+                Err(err) => {
+                    log::warn!(
+                        "Child {} ({p_str}/dna.toml) failed on attempt {}: {err:#}",
+                        dna.id(),
+                        attempt + 1
+                    );
+                    record_child_error(p_str, &err);
+                    if matches!(err.downcast_ref::<Interrupt>(), Some(Interrupt::Cancelled)) {
+                        return Evaluation::cancelled().with_megasteps(spent());
+                    }
+                    thread_rng = Prng::seed_from_u64(thread_rng.random());
+                }
+            }
+        }
+        Evaluation::failed().with_megasteps(spent())
+    }
+
+    // Fitnesses used for selection. Failed children score -inf, like the unevaluated genepool
+    // placeholders, so they are never selected, recorded or counted towards stopping; the
+    // penalty fitness only goes into the log and `fitnesses`.
+    pub(crate) fn get_fitnesses(&mut self, children: &[Evaluation]) -> Vec<f64> {
+        let penalty = self.fault_tolerance.penalty_fitness;
+        let logged: Vec<f64> = children
             .iter()
-            .map(|c| self.fitness_func.eval(c, rng))
+            .map(|c| c.summary.as_ref().map_or(penalty, |s| s.fitness))
             .collect();
-        self.fitnesses.extend_from_slice(&fitnesses);
-        let avg_fitness = fitnesses.iter().sum::<f64>() / fitnesses.len() as f64;
+        let num_cancelled = children.iter().filter(|c| c.cancelled).count();
+        let num_eliminated = children.iter().filter(|c| c.eliminated).count();
+        let num_failed = children
+            .iter()
+            .filter(|c| c.summary.is_none() && !c.cancelled)
+            .count();
+        if num_failed > 0 {
+            log::warn!("{num_failed} children failed and were logged with fitness {penalty}");
+        }
+        if num_cancelled > 0 {
+            log::warn!("{num_cancelled} children were cancelled before they finished");
+        }
+        if num_eliminated > 0 {
+            log::info!("{num_eliminated} children were eliminated on a reduced-fidelity rung");
+        }
+        // cancelled children were never evaluated and eliminated ones never at full fidelity,
+        // both stay out of the fitness history
+        self.fitnesses.extend(
+            logged
+                .iter()
+                .zip(children)
+                .filter(|(_, c)| !c.cancelled && !c.eliminated)
+                .map(|(f, _)| *f),
+        );
+        let avg_fitness = logged.iter().sum::<f64>() / logged.len() as f64;
         log::info!(
             "Children executed: fitnesses = {:?} avg = {avg_fitness}",
-            fitnesses,
+            logged,
         );
-        fitnesses
+        children
+            .iter()
+            .map(|c| c.fitness().unwrap_or(f64::NEG_INFINITY))
+            .collect()
     }
 
-    fn get_polygons(&mut self, children: &[Vmmc]) -> Vec<usize> {
-        let polygons: Vec<usize> = children.iter().map(|c| calc_polygon_count(c, 12)).collect();
+    pub(crate) fn get_polygons(&mut self, children: &[Evaluation]) -> Vec<usize> {
+        let polygons: Vec<usize> = children
+            .iter()
+            .map(|c| c.summary.as_ref().map_or(0, |s| s.polygons))
+            .collect();
         let avg_polygons = polygons.iter().sum::<usize>() / polygons.len();
         log::info!(
             "Children executed: polygons = {:?} avg = {avg_polygons}",
             polygons,
         );
+        let clusters: Vec<(usize, usize)> = children
+            .iter()
+            .map(|c| {
+                c.summary
+                    .as_ref()
+                    .map_or((0, 0), |s| (s.largest_cluster, s.num_clusters))
+            })
+            .collect();
+        log::info!(
+            "Children executed: (largest cluster, clusters) = {:?}",
+            clusters
+        );
         polygons
     }
 
-    fn record_genepool(&self, genepool: &GenePool) {
+    pub(crate) fn record_hall_of_fame(
+        &mut self,
+        candidates: &[Dna],
+        fitnesses: &[f64],
+        gen_idx: usize,
+        gen_dir: &str,
+        output_dir: &str,
+    ) {
+        for (idx, (dna, fitness)) in candidates.iter().zip(fitnesses.iter()).enumerate() {
+            let path = format!("{gen_dir}/{:0>3}", idx);
+            self.hall_of_fame.insert(dna, *fitness, gen_idx, path);
+        }
+        self.save_hall_of_fame(output_dir);
+    }
+
+    pub(crate) fn save_hall_of_fame(&self, output_dir: &str) {
+        if let Some(best) = self.hall_of_fame.best() {
+            log::info!(
+                "Best so far: {} (fitness = {}, generation {})",
+                best.dna.id(),
+                best.fitness,
+                best.generation
+            );
+        }
+        let path = format!("{output_dir}/hall_of_fame.toml");
+        if let Err(err) = write_hall_of_fame_toml(&self.hall_of_fame, &path) {
+            log::warn!("Unable to write hall of fame: {err:#}");
+        }
+    }
+
+    // Returns true if the run should end after the generation that produced `fitnesses`,
+    // which simulated `megasteps` in total
+    pub(crate) fn should_stop(&mut self, fitnesses: &[f64], megasteps: usize) -> bool {
+        match self
+            .stopping_state
+            .update(&self.stopping, fitnesses, megasteps)
+        {
+            Some(reason) => {
+                log::info!("Stopping early: {reason}");
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn record_checkpoint(
+        &self,
+        output_dir: &str,
+        gen_idx: usize,
+        genepools: &[&GenePool],
+    ) {
+        let path = format!("{output_dir}/checkpoint.toml");
+        if let Err(err) = write_checkpoint(
+            &path,
+            gen_idx,
+            self.child_ctr,
+            self.schedule_state.factor(),
+            genepools,
+        ) {
+            log::warn!("Unable to write checkpoint: {err:#}");
+        }
+    }
+
+    pub(crate) fn record_genepool(&self, genepool: &GenePool) {
         let ids: Vec<(usize, f64)> = genepool.iter().map(|(c, fit, _)| (c.id(), *fit)).collect();
         let polygons: Vec<(usize, usize)> = genepool.iter().map(|(c, _, p)| (c.id(), *p)).collect();
         log::info!("Updated genepool: {:?}", ids);
         log::info!("Updated genepool polygons: {:?}\n", polygons);
     }
 
-    pub fn step_all_and_save(&mut self, output_dir: &str, rng: &mut Prng) {
+    // Evaluate a generation on remote workers, only summaries come back over the network.
+    // Cached children are served locally and fresh results are cached like local ones.
+    fn step_generation_remote(
+        &self,
+        pool: &mut WorkerPool,
+        states: &[Dna],
+        rng: &mut Prng,
+        output_dir: &str,
+    ) -> Vec<Evaluation> {
+        if let Err(err) = pool.accept_pending() {
+            log::warn!("Failed to accept new workers: {err:#}");
+        }
+        let mut children: Vec<Option<Evaluation>> = Vec::with_capacity(states.len());
+        let mut pending = Vec::new();
+        let mut jobs = Vec::new();
+        for (idx, dna) in states.iter().enumerate() {
+            let p_str = format!("./{output_dir}/{:0>3}", idx);
+            let seed = rng.random();
+            let key = self
+                .cache
+                .as_ref()
+                .and_then(|_| FitnessCache::key(self, dna, &self.fitness_func, seed));
+            if let Some(summary) = self.cached_child(key, dna, &p_str) {
+                children.push(Some(Evaluation::new(Some(summary), None)));
+                continue;
+            }
+            children.push(None);
+            pending.push((idx, key));
+            jobs.push(Job {
+                sim_params: self.sim_params.clone(),
+                dna: dna.clone(),
+                seed,
+                fitness_func: self.fitness_func.clone(),
+                sim_budget: self.sim_budget.clone(),
+                protocol_penalty: self.protocol_penalty.clone(),
+                init_protocol: self.init_protocol.clone(),
+                max_retries: self.fault_tolerance.max_retries,
+            });
+        }
+        log::info!(
+            "Evaluating {} children on {} remote workers",
+            jobs.len(),
+            pool.num_workers()
+        );
+        let replies = pool.evaluate(jobs, &self.cancel);
+
+        for ((idx, key), reply) in pending.into_iter().zip(replies) {
+            let dna = &states[idx];
+            let p_str = format!("./{output_dir}/{:0>3}", idx);
+            if let Err(err) = record_child_config(&p_str, dna) {
+                log::warn!("Unable to record child {}: {err:#}", dna.id());
+            }
+            children[idx] = Some(match reply {
+                Reply::Done { summary, megasteps } => {
+                    if let Err(err) = record_summary(&p_str, &summary) {
+                        log::warn!("Unable to record child {}: {err:#}", dna.id());
+                    }
+                    self.cache_child(key, dna, &summary);
+                    Evaluation::new(Some(summary), None).with_megasteps(megasteps)
+                }
+                Reply::Failed { error, megasteps } => {
+                    record_child_error(&p_str, &anyhow!(error));
+                    Evaluation::failed().with_megasteps(megasteps)
+                }
+                Reply::Cancelled { megasteps } => Evaluation::cancelled().with_megasteps(megasteps),
+            });
+        }
+        children.into_iter().flatten().collect()
+    }
+
+    pub fn step_all_and_save(&mut self, output_dir: &str, rng: &mut Prng) -> Result<()> {
+        self.hall_of_fame = HallOfFame::new(self.hall_of_fame_size);
+        self.stopping_state = StoppingState::new();
+        if self.cache_fitness {
+            self.cache = Some(FitnessCache::new(output_dir));
+        }
+        self.validate()?;
+        if self.prepared_fitness.is_none() {
+            self.prepare_fitness()?;
+        }
+        match self.optimizer.clone() {
+            Optimizer::Genetic => self.step_genetic_and_save(output_dir, rng)?,
+            Optimizer::MapElites(config) => self.step_map_elites_and_save(config, output_dir, rng),
+            Optimizer::Islands(config) => self.step_islands_and_save(config, output_dir, rng)?,
+            Optimizer::SteadyState(config) => {
+                self.step_steady_state_and_save(config, output_dir, rng)
+            }
+            Optimizer::Bayesian(config) => self.step_bayesian_and_save(config, output_dir, rng)?,
+            Optimizer::HillClimbing(config) => {
+                self.step_hill_climbing_and_save(config, output_dir, rng)
+            }
+            Optimizer::Annealing(config) => self.step_annealing_and_save(config, output_dir, rng),
+            Optimizer::PolicyGradient(config) => {
+                self.step_policy_gradient_and_save(config, output_dir, rng)?
+            }
+        }
         log::info!(
-            "Creating threadpool of {} workers\n",
-            self.generation_size()
+            "Run finished after {} megasteps",
+            self.stopping_state.megasteps()
         );
+        Ok(())
+    }
+
+    fn step_genetic_and_save(&mut self, output_dir: &str, rng: &mut Prng) -> Result<()> {
+        let mut pool = match &self.distributed {
+            Some(config) => Some(WorkerPool::bind(config)?),
+            None => {
+                log::info!(
+                    "Creating threadpool of {} workers\n",
+                    self.generation_size()
+                );
+                None
+            }
+        };
 
         // Create initial generation
+        self.schedule_state = ScheduleState::new(self.mutation_factor);
         let mut candidates = self.initial_candidates();
+        let mut observations = Observations::default();
         let mut genepool: Vec<(Dna, f64, usize)> = candidates[0..self.survivors_per_generation]
             .iter()
-            .map(|c| (c.clone(), 0., 0))
+            .map(|c| (c.clone(), f64::NEG_INFINITY, 0))
             .collect();
 
         for gen_idx in 0..self.num_generations {
@@ -178,25 +756,49 @@ impl EvoEngine {
             // 1.) Execute a generations worth of sims
             let start = Instant::now();
             let gen_dir = format!("./{output_dir}/{:0>3}", gen_idx);
-            let children = self.step_generation_to(&candidates, rng, &gen_dir);
+            let children = match pool.as_mut() {
+                Some(pool) => self.step_generation_remote(pool, &candidates, rng, &gen_dir),
+                None => self.step_generation_to(&candidates, self.fitness(), rng, &gen_dir),
+            };
+            let fitnesses = self.get_fitnesses(&children);
+            let polygons = self.get_polygons(&children);
+            self.observe(&mut observations, &candidates, &children);
 
             let generation_end = Instant::now();
             log::info!("Generation execution time: {:?}", generation_end - start);
             // record_children(output_dir, &candidates, &children, );
 
-            let fitnesses = self.get_fitnesses(&children, rng);
-            let polygons = self.get_polygons(&children);
+            self.record_hall_of_fame(&candidates, &fitnesses, gen_idx, &gen_dir, output_dir);
 
             // 2.) Prune survivors based on fitness function
-            self.prune(&mut genepool, &candidates, fitnesses, polygons);
+            self.prune(&mut genepool, &candidates, fitnesses.clone(), polygons);
             self.record_genepool(&genepool);
             // self.record_genepool_polygons(&polygons);
+            self.record_checkpoint(output_dir, gen_idx, &[genepool.as_slice()]);
+
+            let mutation_factor = self.schedule_state.update(
+                &self.mutation_schedule,
+                gen_idx,
+                self.num_generations,
+                &fitnesses,
+            );
+            log::info!("Mutation factor for next generation: {mutation_factor}");
+
+            if self.should_stop(&fitnesses, megasteps_run(&children)) {
+                break;
+            }
+            if self.is_cancelled() {
+                log::info!("Run cancelled after generation {gen_idx}");
+                break;
+            }
 
             // 3.) Use Mutation function to get back to normal number of sims
-            candidates = self.spawn_children(&genepool, self.children_per_survivor);
+            let surrogate = self.fit_surrogate(&observations);
+            candidates = self.spawn_generation(&genepool, mutation_factor, surrogate.as_ref());
             // let end = Instant::now();
             // log::info!("Time to prep next generation: {:?}", end - generation_end);
         }
+        Ok(())
     }
 
     // generate children from survivors of previously generations
@@ -204,15 +806,53 @@ impl EvoEngine {
         let mut children = Vec::new();
         for (dna, _, _) in genepool.iter() {
             for _ in 0..num_children {
-                // record parent-child relationship
-                self.history.push((dna.id(), self.child_ctr));
-                // create new child
-                let mut child_dna = dna.clone();
-                self.mutate(&mut child_dna);
-                children.push(child_dna);
+                children.push(self.spawn_child(dna));
             }
         }
 
         children
     }
+
+    // elites followed by `children_per_survivor` mutants of every survivor, screened by the
+    // surrogate once it has been fitted
+    pub fn spawn_generation(
+        &mut self,
+        genepool: &GenePool,
+        mutation_factor: f32,
+        surrogate: Option<&Surrogate>,
+    ) -> Vec<Dna> {
+        let mut candidates: Vec<Dna> = elites(genepool, self.elitism)
+            .into_iter()
+            .map(|idx| genepool[idx].0.clone())
+            .collect();
+        for (dna, _, _) in genepool.iter() {
+            match surrogate {
+                Some(surrogate) => candidates.extend(self.spawn_screened(
+                    surrogate,
+                    dna,
+                    self.children_per_survivor,
+                    mutation_factor,
+                )),
+                None => {
+                    for _ in 0..self.children_per_survivor {
+                        candidates.push(self.spawn_child_with(dna, mutation_factor));
+                    }
+                }
+            }
+        }
+        candidates
+    }
+
+    pub fn spawn_child(&mut self, parent: &Dna) -> Dna {
+        self.spawn_child_with(parent, self.schedule_state.factor())
+    }
+
+    pub fn spawn_child_with(&mut self, parent: &Dna, mutation_factor: f32) -> Dna {
+        // record parent-child relationship
+        self.history.push((parent.id(), self.child_ctr));
+        // create new child
+        let mut child_dna = parent.clone();
+        self.mutate(&mut child_dna, mutation_factor);
+        child_dna
+    }
 }
@@ -0,0 +1,150 @@
+/// Multi-fidelity evaluation: every candidate gets a cheap run, only the best get a full one
+use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use vmmc::{Prng, SimParams};
+
+use crate::engine::{Evaluation, EvoEngine};
+use crate::fitness::PreparedFitness;
+use crate::io::write_fidelity_toml;
+use crate::nn::Dna;
+
+fn default_box_scale() -> f64 {
+    1.0
+}
+
+fn default_promote_fraction() -> f64 {
+    0.5
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FidelityLevel {
+    // end the protocol after this many megasteps, the full protocol if unset
+    #[serde(default)]
+    pub max_megasteps: Option<usize>,
+    // scales both box dimensions, the particle count is scaled with the area
+    #[serde(default = "default_box_scale")]
+    pub box_scale: f64,
+}
+
+impl FidelityLevel {
+    pub fn sim_params(&self, sim_params: &SimParams) -> SimParams {
+        let mut sim_params = sim_params.clone();
+        let area_scale = self.box_scale * self.box_scale;
+        sim_params.box_width *= self.box_scale;
+        sim_params.box_height *= self.box_scale;
+        sim_params.initial_particles =
+            (sim_params.initial_particles as f64 * area_scale).round() as usize;
+        sim_params
+    }
+}
+
+/// Successive halving over `levels`, cheapest first, followed by a full-fidelity rung
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultiFidelityConfig {
+    pub levels: Vec<FidelityLevel>,
+    // fraction of each rung promoted to the next one (1/eta in Hyperband terms)
+    #[serde(default = "default_promote_fraction")]
+    pub promote_fraction: f64,
+}
+
+/// Fitnesses of the children that ran on one rung
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FidelityRecord {
+    pub level: usize,
+    pub children: Vec<usize>,
+    pub fitnesses: Vec<f64>,
+}
+
+impl EvoEngine {
+    fn at_fidelity(&self, level: &FidelityLevel) -> EvoEngine {
+        let mut engine = self.clone();
+        engine.sim_params = level.sim_params(&self.sim_params);
+        engine.sim_budget.protocol_limit = level.max_megasteps;
+        engine.multi_fidelity = None;
+        engine
+    }
+
+    // Reduced-fidelity runs go to `{output_dir}/fidelityN/idx`, full-fidelity runs to the usual
+    // `{output_dir}/idx`. Children eliminated early are marked as such and never selected,
+    // only the full-fidelity scores of promoted children go back to selection.
+    pub(crate) fn step_generation_multi_fidelity(
+        &self,
+        config: &MultiFidelityConfig,
+        states: &[Dna],
+        fitness_func: &PreparedFitness,
+        rng: &mut Prng,
+        output_dir: &str,
+    ) -> Vec<Evaluation> {
+        let penalty = self.fault_tolerance.penalty_fitness;
+        let mut evaluations: Vec<Evaluation> =
+            states.iter().map(|_| Evaluation::failed()).collect();
+        let mut active: Vec<usize> = (0..states.len()).collect();
+        let mut records = Vec::new();
+
+        for level_idx in 0..=config.levels.len() {
+            let level = config.levels.get(level_idx);
+            let engine = level.map(|level| self.at_fidelity(level));
+            let engine = engine.as_ref().unwrap_or(self);
+            let level_dir = match level {
+                Some(_) => format!("{output_dir}/fidelity{level_idx}"),
+                None => output_dir.to_string(),
+            };
+
+            let seeds: Vec<u64> = active.iter().map(|_| rng.random()).collect();
+            let results: Vec<Evaluation> = active
+                .par_iter()
+                .zip(seeds.par_iter())
+                .map(|(&idx, &seed)| {
+                    let p_str = format!("./{level_dir}/{:0>3}", idx);
+                    engine.run_child(&states[idx], fitness_func, seed, &p_str)
+                })
+                .collect();
+
+            let fitnesses: Vec<f64> = results
+                .iter()
+                .map(|e| e.summary.as_ref().map_or(penalty, |s| s.fitness))
+                .collect();
+            log::info!(
+                "Fidelity {level_idx}: children = {:?} fitnesses = {:?}",
+                active,
+                fitnesses
+            );
+            records.push(FidelityRecord {
+                level: level_idx,
+                children: active.iter().map(|&idx| states[idx].id()).collect(),
+                fitnesses,
+            });
+            // promote the fittest fraction to the next rung, failed children are never promoted
+            // even if fewer than that fraction succeeded
+            let num_promoted = ((active.len() as f64 * config.promote_fraction).ceil() as usize)
+                .max(1)
+                .min(active.len());
+            let mut ranked: Vec<(usize, f64)> = active
+                .iter()
+                .zip(&results)
+                .filter_map(|(&idx, e)| Some((idx, e.summary.as_ref()?.fitness)))
+                .filter(|(_, fitness)| fitness.is_finite())
+                .collect();
+            ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+            // overwritten by the next rung for every promoted child, which still pays for the
+            // rungs it ran on before
+            for (&idx, mut evaluation) in active.iter().zip(results) {
+                evaluation.eliminated = level.is_some() && evaluation.summary.is_some();
+                evaluation.megasteps += evaluations[idx].megasteps;
+                evaluations[idx] = evaluation;
+            }
+            active = ranked
+                .iter()
+                .take(num_promoted)
+                .map(|(idx, _)| *idx)
+                .collect();
+            active.sort();
+        }
+
+        if let Err(err) = write_fidelity_toml(&records, &format!("{output_dir}/fidelities.toml")) {
+            log::warn!("Unable to write fidelity record: {err:#}");
+        }
+        evaluations
+    }
+}
@@ -1,12 +1,24 @@
-use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use vmmc::particle::IsParticle;
 use vmmc::polygons::calc_polygon_count;
 use vmmc::polygons::calc_polygon_distribution;
 use vmmc::polygons::calc_unitcells;
-use vmmc::tilings::tiling_from_str;
+use vmmc::protocol::{ProtocolIter, ProtocolStep, SynthesisProtocol};
+use vmmc::tilings::{tiling_from_str, UnitCell};
 use vmmc::vmmc::Vmmc;
-use vmmc::Prng;
+use vmmc::{Prng, SimParams};
+
+use crate::reference::{reference_rdf, Features, Reference};
+use crate::structure::{
+    cluster_sizes, curve_similarity, global_bond_order, largest_cluster_fraction, local_bond_order,
+    ordered_fraction, unitcell_labels, weight_averaged_cluster_size, wrong_polygon_fraction,
+    CorrelationFunction, CorrelationTarget,
+};
 
 type BondOrderMatrix = ((u32, u32), (u32, u32));
 type NormalizedBondOrderMatrix = ((f64, f64), (f64, f64));
@@ -20,10 +32,245 @@ pub enum FitnessFunc {
     BondOrder(BondOrderMatrix),
     // target unit cell structure
     Unitcell(String),
+    // unit cells of `target` less `competitor_weight` times the unit cells of every competitor
+    Selectivity {
+        target: String,
+        competitors: Vec<String>,
+        #[serde(default = "default_competitor_weight")]
+        competitor_weight: f64,
+    },
+    // fraction of particles in at least one target unit cell, less `polygon_weight` times the
+    // fraction of polygons that do not occur in the tiling
+    UnitcellCoverage {
+        tiling: String,
+        #[serde(default = "default_polygon_weight")]
+        polygon_weight: f64,
+        // write per-particle in-cell / defect labels next to each child's geometry
+        #[serde(default)]
+        report_labels: bool,
+    },
+    // n-fold bond-orientational order (4 for square, 6 for hexagonal): the global |psi_n|,
+    // or the fraction of particles with a local |psi_n| of at least `threshold`.
+    // Neighbours are bonded partners, or every particle within `cutoff` if it is set.
+    BondOrientational {
+        n: u32,
+        #[serde(default)]
+        threshold: Option<f64>,
+        #[serde(default)]
+        cutoff: Option<f64>,
+    },
+    // particles in the largest connected cluster of bonded particles
+    LargestCluster,
+    // fraction of all particles in the largest cluster
+    LargestClusterFraction,
+    // sum s^2 / sum s over cluster sizes s
+    WeightAveragedClusterSize,
+    // similarity of the final configuration to a reference configuration file. Bond angles
+    // are measured between particles closer than `neighbour_cutoff`, g(r) up to `r_max`.
+    ReferenceSimilarity {
+        path: String,
+        #[serde(default = "default_neighbour_cutoff")]
+        neighbour_cutoff: f64,
+        #[serde(default = "default_reference_r_max")]
+        r_max: f64,
+    },
+    // similarity of g(r) or S(k) of the final positions to a target curve
+    Correlation {
+        function: CorrelationFunction,
+        target: CorrelationTarget,
+    },
+    // `inner` averaged over the last `fraction` of the megasteps
+    TimeAveraged {
+        inner: Box<FitnessFunc>,
+        fraction: f64,
+    },
+    // mean of `inner` over the run (area under the curve per megastep)
+    AreaUnderCurve(Box<FitnessFunc>),
+    // 1 - (first megastep where `inner` reaches `threshold`) / megasteps, 0 if it never does
+    TimeToTarget {
+        inner: Box<FitnessFunc>,
+        threshold: f64,
+    },
+}
+
+// Parsed tilings, target curves and reference configurations of a fitness function
+#[derive(Default)]
+struct PreparedData {
+    unitcells: HashMap<String, UnitCell>,
+    curves: HashMap<String, Vec<f64>>,
+    // reference configurations reduced to what they are compared on, by `reference_key`
+    references: HashMap<String, Features>,
+}
+
+impl PreparedData {
+    fn add_tiling(&mut self, tiling: &str) -> Result<()> {
+        if !self.unitcells.contains_key(tiling) {
+            let unitcell = tiling_from_str(tiling)
+                .map_err(|e| anyhow!("Invalid tiling string \"{tiling}\": {e:?}"))?;
+            self.unitcells.insert(tiling.to_string(), unitcell);
+        }
+        Ok(())
+    }
+
+    fn unitcell(&self, tiling: &str) -> &UnitCell {
+        &self.unitcells[tiling]
+    }
+}
+
+fn curve_key(function: &CorrelationFunction, target: &CorrelationTarget) -> String {
+    format!("{function:?} {target:?}")
+}
+
+fn reference_key(path: &str, neighbour_cutoff: f64, r_max: f64) -> String {
+    format!("{path} {neighbour_cutoff} {r_max}")
+}
+
+/// A `FitnessFunc` checked against the simulation and with its inputs parsed once, before any
+/// simulation runs. Cheap to clone.
+#[derive(Clone)]
+pub struct PreparedFitness {
+    func: FitnessFunc,
+    data: Arc<PreparedData>,
+}
+
+/// `inner` of a trajectory-based fitness, sampled once per megastep
+pub type Trajectory = Arc<Mutex<Vec<f64>>>;
+
+// Evaluates an observable on the state handed to every protocol step
+struct ObservingIter {
+    inner: Box<dyn ProtocolIter>,
+    observable: PreparedFitness,
+    rng: Prng,
+    trajectory: Trajectory,
+}
+
+impl ProtocolIter for ObservingIter {
+    fn next(&mut self, vmmc: &Vmmc) -> Option<ProtocolStep> {
+        let value = self.observable.eval(vmmc, &mut self.rng);
+        self.trajectory.lock().unwrap().push(value);
+        self.inner.next(vmmc)
+    }
+
+    fn peek(&self, vmmc: &Vmmc) -> ProtocolStep {
+        self.inner.peek(vmmc)
+    }
+
+    fn start(&self) -> ProtocolStep {
+        self.inner.start()
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
 }
 
 impl FitnessFunc {
-    pub fn eval(&self, vmmc: &Vmmc, rng: &mut Prng) -> f64 {
+    // Validate against `sim_params` and parse every tiling, target curve and reference file
+    pub fn prepare(&self, sim_params: &SimParams) -> Result<PreparedFitness> {
+        let mut data = PreparedData::default();
+        self.prepare_into(&mut data, sim_params)
+            .with_context(|| format!("Invalid fitness function {self:?}"))?;
+        Ok(PreparedFitness {
+            func: self.clone(),
+            data: Arc::new(data),
+        })
+    }
+
+    // False if the score is random even for a fixed simulation, at any level of nesting
+    pub fn is_deterministic(&self) -> bool {
+        match self {
+            FitnessFunc::Random => false,
+            FitnessFunc::TimeAveraged { inner, .. }
+            | FitnessFunc::AreaUnderCurve(inner)
+            | FitnessFunc::TimeToTarget { inner, .. } => inner.is_deterministic(),
+            _ => true,
+        }
+    }
+
+    fn prepare_into(&self, data: &mut PreparedData, sim_params: &SimParams) -> Result<()> {
+        match self {
+            FitnessFunc::Random
+            | FitnessFunc::PolygonSum
+            | FitnessFunc::ShapeDist
+            | FitnessFunc::LargestCluster
+            | FitnessFunc::LargestClusterFraction
+            | FitnessFunc::WeightAveragedClusterSize => {}
+            FitnessFunc::BondOrder(matrix) => {
+                let ((a, b), (c, d)) = *matrix;
+                if a + b + c + d == 0 {
+                    bail!("BondOrder matrix {matrix:?} sums to zero");
+                }
+                require_two_species("BondOrder", sim_params)?;
+            }
+            FitnessFunc::Unitcell(tiling) | FitnessFunc::UnitcellCoverage { tiling, .. } => {
+                data.add_tiling(tiling)?
+            }
+            FitnessFunc::Selectivity {
+                target,
+                competitors,
+                ..
+            } => {
+                data.add_tiling(target)?;
+                for tiling in competitors {
+                    data.add_tiling(tiling)?;
+                }
+            }
+            FitnessFunc::BondOrientational {
+                n,
+                threshold,
+                cutoff,
+            } => {
+                if *n == 0 {
+                    bail!("BondOrientational needs n >= 1");
+                }
+                if let Some(threshold) = threshold {
+                    if !(0.0..=1.0).contains(threshold) {
+                        bail!("BondOrientational threshold {threshold} is outside [0, 1]");
+                    }
+                }
+                if let Some(cutoff) = cutoff {
+                    if *cutoff <= 0.0 {
+                        bail!("BondOrientational cutoff {cutoff} must be positive");
+                    }
+                }
+            }
+            FitnessFunc::ReferenceSimilarity {
+                path,
+                neighbour_cutoff,
+                r_max,
+            } => {
+                if !(*neighbour_cutoff > 0.0 && neighbour_cutoff.is_finite()) {
+                    bail!(
+                        "ReferenceSimilarity neighbour_cutoff {neighbour_cutoff} must be positive"
+                    );
+                }
+                let rdf = reference_rdf(*r_max);
+                rdf.validate().context("ReferenceSimilarity")?;
+                let key = reference_key(path, *neighbour_cutoff, *r_max);
+                if !data.references.contains_key(&key) {
+                    let features = Reference::load(path)?.features(*neighbour_cutoff, &rdf);
+                    data.references.insert(key, features);
+                }
+            }
+            FitnessFunc::Correlation { function, target } => {
+                function.validate().context("Correlation")?;
+                data.curves
+                    .insert(curve_key(function, target), target.curve(function)?);
+            }
+            FitnessFunc::TimeAveraged { inner, fraction } => {
+                if !(*fraction > 0.0 && *fraction <= 1.0) {
+                    bail!("TimeAveraged fraction {fraction} is outside (0, 1]");
+                }
+                inner.prepare_into(data, sim_params)?;
+            }
+            FitnessFunc::AreaUnderCurve(inner) | FitnessFunc::TimeToTarget { inner, .. } => {
+                inner.prepare_into(data, sim_params)?
+            }
+        }
+        Ok(())
+    }
+
+    fn eval_with(&self, data: &PreparedData, vmmc: &Vmmc, rng: &mut Prng) -> f64 {
         match self {
             FitnessFunc::Random => rng.random(), // we don't differentiate between different simulations
             FitnessFunc::PolygonSum => calc_polygon_count(vmmc, 12) as f64,
@@ -46,12 +293,298 @@ impl FitnessFunc {
                 }
             }
             FitnessFunc::Unitcell(tiling_string) => {
-                // Convert string to UnitCell and count instances
-                let unitcell = tiling_from_str(tiling_string).expect("Invalid tiling string");
-                let calced_unitcells = calc_unitcells(vmmc, 12, &unitcell);
+                // Count instances of the parsed UnitCell
+                let calced_unitcells = calc_unitcells(vmmc, 12, data.unitcell(tiling_string));
                 calced_unitcells.len() as f64
             }
+            FitnessFunc::Selectivity {
+                target,
+                competitors,
+                competitor_weight,
+            } => {
+                let count =
+                    |tiling: &String| calc_unitcells(vmmc, 12, data.unitcell(tiling)).len() as f64;
+                count(target) - competitor_weight * competitors.iter().map(count).sum::<f64>()
+            }
+            FitnessFunc::UnitcellCoverage {
+                tiling,
+                polygon_weight,
+                ..
+            } => {
+                let labels = unitcell_labels(vmmc, data.unitcell(tiling));
+                if labels.is_empty() {
+                    return 0.0;
+                }
+                let covered = labels.iter().filter(|(_, in_cell)| *in_cell).count();
+                covered as f64 / labels.len() as f64
+                    - polygon_weight * wrong_polygon_fraction(vmmc, tiling)
+            }
+            FitnessFunc::BondOrientational {
+                n,
+                threshold,
+                cutoff,
+            } => {
+                let local = local_bond_order(vmmc, *n, *cutoff);
+                match threshold {
+                    Some(threshold) => ordered_fraction(&local, *threshold),
+                    None => global_bond_order(&local),
+                }
+            }
+            FitnessFunc::LargestCluster => cluster_sizes(vmmc).first().copied().unwrap_or(0) as f64,
+            FitnessFunc::LargestClusterFraction => largest_cluster_fraction(&cluster_sizes(vmmc)),
+            FitnessFunc::WeightAveragedClusterSize => {
+                weight_averaged_cluster_size(&cluster_sizes(vmmc))
+            }
+            FitnessFunc::ReferenceSimilarity {
+                path,
+                neighbour_cutoff,
+                r_max,
+            } => Reference::from_vmmc(vmmc).similarity(
+                &data.references[&reference_key(path, *neighbour_cutoff, *r_max)],
+                *neighbour_cutoff,
+                &reference_rdf(*r_max),
+            ),
+            FitnessFunc::Correlation { function, target } => {
+                let target = &data.curves[&curve_key(function, target)];
+                curve_similarity(&function.eval(vmmc), target)
+            }
+            // without a trajectory only the final state is available
+            FitnessFunc::TimeAveraged { inner, .. }
+            | FitnessFunc::AreaUnderCurve(inner)
+            | FitnessFunc::TimeToTarget { inner, .. } => inner.eval_with(data, vmmc, rng),
+        }
+    }
+
+    fn observable(&self) -> Option<&FitnessFunc> {
+        match self {
+            FitnessFunc::TimeAveraged { inner, .. }
+            | FitnessFunc::AreaUnderCurve(inner)
+            | FitnessFunc::TimeToTarget { inner, .. } => Some(inner),
+            _ => None,
+        }
+    }
+}
+
+impl PreparedFitness {
+    pub fn func(&self) -> &FitnessFunc {
+        &self.func
+    }
+
+    pub fn eval(&self, vmmc: &Vmmc, rng: &mut Prng) -> f64 {
+        self.func.eval_with(&self.data, vmmc, rng)
+    }
+
+    // Per-particle (id, in a target unit cell) labels, if this fitness reports them
+    pub fn particle_labels(&self, vmmc: &Vmmc) -> Option<Vec<(usize, bool)>> {
+        match &self.func {
+            FitnessFunc::UnitcellCoverage {
+                tiling,
+                report_labels: true,
+                ..
+            } => Some(unitcell_labels(vmmc, self.data.unitcell(tiling))),
+            _ => None,
+        }
+    }
+
+    // Wrap a protocol so the observables needed by trajectory-based fitness get recorded.
+    // Random observables draw from a stream seeded by `rng`, so every child and retry differs.
+    pub fn observe(
+        &self,
+        protocol_iter: Box<dyn ProtocolIter>,
+        rng: &mut Prng,
+    ) -> (Box<dyn ProtocolIter>, Trajectory) {
+        let trajectory = Trajectory::default();
+        let Some(observable) = self.func.observable() else {
+            return (protocol_iter, trajectory);
+        };
+        let observing = ObservingIter {
+            inner: protocol_iter,
+            observable: PreparedFitness {
+                func: observable.clone(),
+                data: self.data.clone(),
+            },
+            rng: Prng::seed_from_u64(rng.random()),
+            trajectory: trajectory.clone(),
+        };
+        (Box::new(observing), trajectory)
+    }
+
+    // Fitness of a finished child: `eval_trajectory` minus the penalty on its realised protocol
+    pub fn score(
+        &self,
+        vmmc: &Vmmc,
+        trajectory: &Trajectory,
+        proto: &[ProtocolStep],
+        penalty: &ProtocolPenalty,
+        init_protocol: &SynthesisProtocol,
+        rng: &mut Prng,
+    ) -> f64 {
+        self.eval_trajectory(vmmc, trajectory, rng) - penalty.eval(proto, init_protocol)
+    }
+
+    // Like `eval`, for a run whose protocol was wrapped by `observe`
+    pub fn eval_trajectory(&self, vmmc: &Vmmc, trajectory: &Trajectory, rng: &mut Prng) -> f64 {
+        let Some(inner) = self.func.observable() else {
+            return self.eval(vmmc, rng);
+        };
+        // the protocol is asked for one more step after the last one, so the final state is
+        // already the last sample
+        let mut series = trajectory.lock().unwrap().clone();
+        if series.is_empty() {
+            series.push(inner.eval_with(&self.data, vmmc, rng));
+        }
+        let len = series.len();
+        match &self.func {
+            FitnessFunc::TimeAveraged { fraction, .. } => {
+                let n = ((len as f64 * fraction).ceil() as usize).clamp(1, len);
+                series[len - n..].iter().sum::<f64>() / n as f64
+            }
+            FitnessFunc::AreaUnderCurve(_) => {
+                if len == 1 {
+                    return series[0];
+                }
+                let area: f64 = series.windows(2).map(|w| (w[0] + w[1]) / 2.0).sum();
+                area / (len - 1) as f64
+            }
+            FitnessFunc::TimeToTarget { threshold, .. } => {
+                match series.iter().position(|v| *v >= *threshold) {
+                    Some(t) => 1.0 - t as f64 / len as f64,
+                    None => 0.0,
+                }
+            }
+            _ => unreachable!("only trajectory-based fitness has an observable"),
+        }
+    }
+}
+
+// The 2x2 bond order matrix is only defined for two particle species
+pub(crate) fn require_two_species(what: &str, sim_params: &SimParams) -> Result<()> {
+    let species = sim_params.shapes.len();
+    if species != 2 {
+        bail!("{what} compares 2 particle species, the simulation has {species}");
+    }
+    Ok(())
+}
+
+fn default_competitor_weight() -> f64 {
+    1.0
+}
+
+fn default_polygon_weight() -> f64 {
+    1.0
+}
+
+fn default_neighbour_cutoff() -> f64 {
+    1.5
+}
+
+fn default_reference_r_max() -> f64 {
+    5.0
+}
+
+fn default_mu_bounds() -> (f64, f64) {
+    (-20.0, 20.0)
+}
+
+fn default_epsilon_bounds() -> (f64, f64) {
+    (0.0, 20.0)
+}
+
+fn default_extreme_margin() -> f64 {
+    0.5
+}
+
+/// Weighted penalties on the realised protocol, subtracted from the fitness.
+/// Every weight defaults to 0, which leaves the fitness untouched.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProtocolPenalty {
+    // mean |d mu| + |d epsilon| between consecutive megasteps, so that it does not grow
+    // with the protocol length
+    #[serde(default)]
+    pub total_variation: f64,
+    // largest |d mu| or |d epsilon| between consecutive megasteps
+    #[serde(default)]
+    pub max_slope: f64,
+    // mean |mu - mu_0| + |epsilon - epsilon_0| against `init_protocol`
+    #[serde(default)]
+    pub init_distance: f64,
+    // fraction of megasteps with mu or epsilon within `extreme_margin` of its bounds
+    #[serde(default)]
+    pub extreme_time: f64,
+    #[serde(default = "default_mu_bounds")]
+    pub mu_bounds: (f64, f64),
+    #[serde(default = "default_epsilon_bounds")]
+    pub epsilon_bounds: (f64, f64),
+    #[serde(default = "default_extreme_margin")]
+    pub extreme_margin: f64,
+}
+
+impl Default for ProtocolPenalty {
+    fn default() -> Self {
+        Self {
+            total_variation: 0.0,
+            max_slope: 0.0,
+            init_distance: 0.0,
+            extreme_time: 0.0,
+            mu_bounds: default_mu_bounds(),
+            epsilon_bounds: default_epsilon_bounds(),
+            extreme_margin: default_extreme_margin(),
+        }
+    }
+}
+
+impl ProtocolPenalty {
+    fn is_extreme(&self, value: f64, (min, max): (f64, f64)) -> bool {
+        value <= min + self.extreme_margin || value >= max - self.extreme_margin
+    }
+
+    pub fn eval(&self, proto: &[ProtocolStep], init_protocol: &SynthesisProtocol) -> f64 {
+        if proto.is_empty() {
+            return 0.0;
+        }
+        let mut penalty = 0.0;
+
+        let slopes: Vec<(f64, f64)> = proto
+            .windows(2)
+            .map(|w| {
+                (
+                    (w[1].chemical_potential() - w[0].chemical_potential()).abs(),
+                    (w[1].interaction_energy() - w[0].interaction_energy()).abs(),
+                )
+            })
+            .collect();
+        if self.total_variation != 0.0 {
+            let tv: f64 = slopes.iter().map(|(mu, eps)| mu + eps).sum();
+            penalty += self.total_variation * tv / slopes.len().max(1) as f64;
+        }
+        if self.max_slope != 0.0 {
+            let max = slopes
+                .iter()
+                .fold(0.0_f64, |a, (mu, eps)| a.max(*mu).max(*eps));
+            penalty += self.max_slope * max;
+        }
+        if self.init_distance != 0.0 {
+            let len = proto.len().min(init_protocol.len());
+            let dist: f64 = (0..len)
+                .map(|t| {
+                    (proto[t].chemical_potential() - init_protocol.chemical_potential(t)).abs()
+                        + (proto[t].interaction_energy() - init_protocol.interaction_energy(t))
+                            .abs()
+                })
+                .sum();
+            penalty += self.init_distance * dist / len.max(1) as f64;
+        }
+        if self.extreme_time != 0.0 {
+            let extreme = proto
+                .iter()
+                .filter(|step| {
+                    self.is_extreme(step.chemical_potential(), self.mu_bounds)
+                        || self.is_extreme(step.interaction_energy(), self.epsilon_bounds)
+                })
+                .count();
+            penalty += self.extreme_time * extreme as f64 / proto.len() as f64;
         }
+        penalty
     }
 }
 
@@ -81,7 +614,7 @@ fn calculate_manhattan_distance(
 }
 
 // Calculate normalized 2x2 matrix of bond orders for 2 vertex system
-fn calculate_bond_order_matrix(vmmc: &Vmmc) -> NormalizedBondOrderMatrix {
+pub(crate) fn calculate_bond_order_matrix(vmmc: &Vmmc) -> NormalizedBondOrderMatrix {
     let mut counts = [[0; 2]; 2];
 
     // Count interactions between particles
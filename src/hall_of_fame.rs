@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+use crate::nn::Dna;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HallOfFameEntry {
+    pub fitness: f64,
+    pub generation: usize,
+    pub path: String,
+    pub dna: Dna,
+}
+
+/// The `size` fittest evaluations seen over the whole run, best first
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct HallOfFame {
+    #[serde(skip)]
+    size: usize,
+    entries: Vec<HallOfFameEntry>,
+}
+
+impl HallOfFame {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn entries(&self) -> &[HallOfFameEntry] {
+        &self.entries
+    }
+
+    pub fn best(&self) -> Option<&HallOfFameEntry> {
+        self.entries.first()
+    }
+
+    // Returns true if the evaluation made it into the hall of fame. A genome keeps a single
+    // entry, its best evaluation. NaN and the -inf of failed children are never recorded.
+    pub fn insert(&mut self, dna: &Dna, fitness: f64, generation: usize, path: String) -> bool {
+        if !fitness.is_finite() {
+            return false;
+        }
+        if let Some(idx) = self.entries.iter().position(|e| e.dna.id() == dna.id()) {
+            if self.entries[idx].fitness >= fitness {
+                return false;
+            }
+            self.entries.remove(idx);
+        }
+        let pos = self.entries.partition_point(|e| e.fitness >= fitness);
+        if pos >= self.size {
+            return false;
+        }
+        let entry = HallOfFameEntry {
+            fitness,
+            generation,
+            path,
+            dna: dna.clone(),
+        };
+        self.entries.insert(pos, entry);
+        self.entries.truncate(self.size);
+        true
+    }
+}
@@ -1,25 +1,111 @@
+use anyhow::Result;
 use std::fs::create_dir_all;
+use vmmc::particle::IsParticle;
 use vmmc::{
     io::{write_colored_geometry_png, write_geometry_png, write_protocols_png, write_stats},
     protocol::ProtocolStep,
     vmmc::Vmmc,
 };
 
+use crate::archive::{Archive, Elite};
+use crate::bayesian::Posterior;
+use crate::distributed::Summary;
+use crate::fidelity::FidelityRecord;
+use crate::fitness::PreparedFitness;
+use crate::hall_of_fame::HallOfFame;
 use crate::nn::Dna;
+use crate::reference::Reference;
+use crate::structure::{box_size, cluster_size_distribution, cluster_sizes};
+use crate::sweep::{SweepConfig, SweepPoint, SweepRow};
 
-pub fn record_child_config(p_str: &str, dna: &Dna) {
+pub fn record_child_config(p_str: &str, dna: &Dna) -> Result<()> {
     let out_path = std::path::Path::new(&p_str);
-    create_dir_all(out_path).unwrap();
-    let toml = toml::to_string(dna).unwrap();
-    std::fs::write(format!("{p_str}/dna.toml"), toml).expect("Unable to write file");
+    create_dir_all(out_path)?;
+    let toml = toml::to_string(dna)?;
+    std::fs::write(format!("{p_str}/dna.toml"), toml)?;
+    Ok(())
 }
 
-pub fn record_child(p_str: &str, child: &Vmmc, proto: Vec<ProtocolStep>) {
+pub fn record_child(p_str: &str, child: &Vmmc, proto: Vec<ProtocolStep>) -> Result<()> {
     write_geometry_png(child, &format!("{p_str}/geometry.png"));
     write_colored_geometry_png(child, &format!("{p_str}/colored_geometry.png"));
     // let protocol_iter = dna.protocol_iter();
     write_protocols_png(proto, &format!("{p_str}/protocols.png"));
-    write_stats(child, &format!("{p_str}/stats.txt"))
+    write_stats(child, &format!("{p_str}/stats.txt"));
+    write_cluster_sizes(child, &format!("{p_str}/clusters.txt"))?;
+    Reference::from_vmmc(child).write(&format!("{p_str}/positions.txt"))?;
+    Ok(())
+}
+
+// In-cell / defect labels of a `UnitcellCoverage` fitness, as `id x y in_cell` lines and an image
+pub fn record_particle_labels(
+    p_str: &str,
+    child: &Vmmc,
+    fitness_func: &PreparedFitness,
+) -> Result<()> {
+    let Some(labels) = fitness_func.particle_labels(child) else {
+        return Ok(());
+    };
+    let mut out = String::new();
+    let mut points = Vec::with_capacity(labels.len());
+    for (id, in_cell) in labels {
+        let pos = child.particle(id).pos();
+        out.push_str(&format!("{id} {} {} {in_cell}\n", pos.x(), pos.y()));
+        points.push(((pos.x(), pos.y()), in_cell));
+    }
+    std::fs::write(format!("{p_str}/labels.txt"), out)?;
+    write_labels_png(&points, box_size(child), &format!("{p_str}/labels.png"));
+    Ok(())
+}
+
+// blue = in a target unit cell, red = defect
+fn write_labels_png(points: &[((f64, f64), bool)], (width, height): (f64, f64), pathname: &str) {
+    use plotters::prelude::*;
+
+    let root_area = BitMapBackend::new(pathname, (800, 800)).into_drawing_area();
+    root_area.fill(&WHITE).unwrap();
+
+    let mut ctx = ChartBuilder::on(&root_area)
+        .caption("Unit cell coverage", ("sans-serif", 32))
+        .build_cartesian_2d(0.0..width, 0.0..height)
+        .unwrap();
+
+    ctx.draw_series(points.iter().map(|(point, in_cell)| {
+        let color = if *in_cell {
+            RGBColor(0x09, 0x61, 0x91)
+        } else {
+            RGBColor(0xd6, 0x27, 0x28)
+        };
+        Circle::new(*point, 4, color.filled())
+    }))
+    .unwrap();
+}
+
+// Number of clusters of each size, one `size count` line per occupied size
+pub fn write_cluster_sizes(child: &Vmmc, pathname: &str) -> Result<()> {
+    let dist = cluster_size_distribution(&cluster_sizes(child));
+    let mut out = String::new();
+    for (size, count) in dist.iter().enumerate().filter(|(_, c)| **c > 0) {
+        out.push_str(&format!("{size} {count}\n"));
+    }
+    std::fs::write(pathname, out)?;
+    Ok(())
+}
+
+// Stands in for the geometry and stats of children simulated on a remote worker
+pub fn record_summary(p_str: &str, summary: &Summary) -> Result<()> {
+    let toml = toml::to_string(summary)?;
+    std::fs::write(format!("{p_str}/summary.toml"), toml)?;
+    Ok(())
+}
+
+// Best effort: a child that failed to write its outputs may also fail here
+pub fn record_child_error(p_str: &str, err: &anyhow::Error) {
+    let res = create_dir_all(p_str)
+        .and_then(|_| std::fs::write(format!("{p_str}/error.txt"), format!("{err:#}\n")));
+    if let Err(io_err) = res {
+        log::warn!("Unable to record error for {p_str}: {io_err}");
+    }
 }
 
 pub fn write_progress_png(fitnesses: &[f64], pathname: &str) {
@@ -71,3 +157,293 @@ pub fn write_progress_png(fitnesses: &[f64], pathname: &str) {
     ctx.draw_series(LineSeries::new(best_score_line, best_score_line_style))
         .unwrap();
 }
+
+#[derive(serde::Serialize)]
+struct CheckpointEntry<'a> {
+    island: usize,
+    fitness: f64,
+    polygons: usize,
+    dna: &'a Dna,
+}
+
+#[derive(serde::Serialize)]
+struct Checkpoint<'a> {
+    generation: usize,
+    child_ctr: usize,
+    mutation_factor: f32,
+    genepool: Vec<CheckpointEntry<'a>>,
+}
+
+// Snapshot of the genepool(s) after a generation, rewritten every generation
+pub fn write_checkpoint(
+    pathname: &str,
+    generation: usize,
+    child_ctr: usize,
+    mutation_factor: f32,
+    genepools: &[&[(Dna, f64, usize)]],
+) -> Result<()> {
+    let genepool = genepools
+        .iter()
+        .enumerate()
+        .flat_map(|(island, genepool)| {
+            // members that were never evaluated (cancelled or failed placeholders) are left
+            // out, so a resumed run evaluates them again
+            genepool
+                .iter()
+                .filter(|(_, fitness, _)| fitness.is_finite())
+                .map(move |(dna, fitness, polygons)| CheckpointEntry {
+                    island,
+                    fitness: *fitness,
+                    polygons: *polygons,
+                    dna,
+                })
+        })
+        .collect();
+    let checkpoint = Checkpoint {
+        generation,
+        child_ctr,
+        mutation_factor,
+        genepool,
+    };
+    std::fs::write(pathname, toml::to_string(&checkpoint)?)?;
+    Ok(())
+}
+
+pub fn write_hall_of_fame_toml(hall_of_fame: &HallOfFame, pathname: &str) -> Result<()> {
+    std::fs::write(pathname, toml::to_string(hall_of_fame)?)?;
+    Ok(())
+}
+
+// The trained Microstate policy as a genome, loadable like any `dna.toml`
+pub fn write_policy_toml(policy: &Dna, pathname: &str) -> Result<()> {
+    std::fs::write(pathname, toml::to_string(policy)?)?;
+    Ok(())
+}
+
+pub fn write_fidelity_toml(records: &[FidelityRecord], pathname: &str) -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct FidelityToml<'a> {
+        levels: &'a [FidelityRecord],
+    }
+
+    std::fs::write(
+        pathname,
+        toml::to_string(&FidelityToml { levels: records })?,
+    )?;
+    Ok(())
+}
+
+pub fn write_posterior_toml(posterior: &Posterior, pathname: &str) -> Result<()> {
+    std::fs::write(pathname, toml::to_string(posterior)?)?;
+    Ok(())
+}
+
+pub fn write_archive_toml(archive: &Archive, pathname: &str) -> Result<()> {
+    #[derive(serde::Serialize)]
+    struct ArchiveToml<'a> {
+        elites: Vec<&'a Elite>,
+    }
+
+    let elites = ArchiveToml {
+        elites: archive.elites().collect(),
+    };
+    std::fs::write(pathname, toml::to_string(&elites)?)?;
+    Ok(())
+}
+
+pub fn write_archive_png(archive: &Archive, pathname: &str) {
+    use plotters::prelude::*;
+
+    let config = archive.config();
+    let root_area = BitMapBackend::new(pathname, (1000, 800)).into_drawing_area();
+    root_area.fill(&WHITE).unwrap();
+
+    let min_fitness = archive
+        .elites()
+        .fold(f64::INFINITY, |a, e| a.min(e.fitness));
+    let max_fitness = archive
+        .elites()
+        .fold(f64::NEG_INFINITY, |a, e| a.max(e.fitness));
+
+    let mut ctx = ChartBuilder::on(&root_area)
+        .set_label_area_size(LabelAreaPosition::Left, 48)
+        .set_label_area_size(LabelAreaPosition::Bottom, 48)
+        .caption("MAP-Elites archive", ("sans-serif", 32))
+        .build_cartesian_2d(config.x.min..config.x.max, config.y.min..config.y.max)
+        .unwrap();
+
+    ctx.configure_mesh()
+        .x_desc(format!("{:?}", config.x.descriptor))
+        .y_desc(format!("{:?}", config.y.descriptor))
+        .draw()
+        .unwrap();
+
+    // blue = least fit, red = most fit
+    ctx.draw_series(archive.elites().map(|e| {
+        let (x0, x1) = config.x.bin_bounds(e.cell.0);
+        let (y0, y1) = config.y.bin_bounds(e.cell.1);
+        let t = if max_fitness > min_fitness {
+            (e.fitness - min_fitness) / (max_fitness - min_fitness)
+        } else {
+            1.0
+        };
+        Rectangle::new(
+            [(x0, y0), (x1, y1)],
+            HSLColor(0.66 * (1.0 - t), 0.8, 0.5).filled(),
+        )
+    }))
+    .unwrap();
+}
+
+pub fn write_sweep_csv(names: &[String], rows: &[SweepRow], pathname: &str) -> Result<()> {
+    let mut csv = String::new();
+    csv.push_str(&names.join(","));
+    csv.push_str(",replicate,fitness,polygons,failed\n");
+    for row in rows {
+        for param in &row.params {
+            csv.push_str(&format!("{param},"));
+        }
+        csv.push_str(&format!(
+            "{},{},{},{}\n",
+            row.replicate, row.fitness, row.polygons, row.failed
+        ));
+    }
+    std::fs::write(pathname, csv)?;
+    Ok(())
+}
+
+// One line per point, the mean is empty if every replicate failed
+pub fn write_sweep_points_csv(
+    names: &[String],
+    points: &[SweepPoint],
+    pathname: &str,
+) -> Result<()> {
+    let mut csv = String::new();
+    csv.push_str(&names.join(","));
+    csv.push_str(",mean_fitness,succeeded,failed\n");
+    for point in points {
+        for param in &point.params {
+            csv.push_str(&format!("{param},"));
+        }
+        let mean = point.mean_fitness.map_or(String::new(), |f| f.to_string());
+        csv.push_str(&format!("{mean},{},{}\n", point.succeeded, point.failed));
+    }
+    std::fs::write(pathname, csv)?;
+    Ok(())
+}
+
+// Mean fitness of the successful replicates over the first two parameters, or against the
+// only one. Cells where every replicate failed are grey.
+pub fn write_sweep_png(config: &SweepConfig, rows: &[SweepRow], pathname: &str) {
+    use plotters::prelude::*;
+
+    if rows.is_empty() || config.parameters.is_empty() {
+        return;
+    }
+    let root_area = BitMapBackend::new(pathname, (1000, 800)).into_drawing_area();
+    root_area.fill(&WHITE).unwrap();
+
+    // average over successful replicates and any parameters past the second
+    let mut cells: Vec<((f64, f64), Vec<f64>)> = Vec::new();
+    for row in rows {
+        let key = (row.params[0], row.params.get(1).cloned().unwrap_or(0.0));
+        let idx = match cells.iter().position(|(k, _)| *k == key) {
+            Some(idx) => idx,
+            None => {
+                cells.push((key, Vec::new()));
+                cells.len() - 1
+            }
+        };
+        if !row.failed {
+            cells[idx].1.push(row.fitness);
+        }
+    }
+    let means: Vec<((f64, f64), Option<f64>)> = cells
+        .into_iter()
+        .map(|(key, fits)| {
+            let mean = (!fits.is_empty()).then(|| fits.iter().sum::<f64>() / fits.len() as f64);
+            (key, mean)
+        })
+        .collect();
+    let min_fitness = means
+        .iter()
+        .filter_map(|(_, f)| *f)
+        .fold(f64::INFINITY, f64::min);
+    let max_fitness = means
+        .iter()
+        .filter_map(|(_, f)| *f)
+        .fold(f64::NEG_INFINITY, f64::max);
+    if !min_fitness.is_finite() {
+        log::warn!("Every sweep replicate failed, not plotting {pathname}");
+        return;
+    }
+
+    let x = &config.parameters[0];
+    let x_half = config.cell_width(0) / 2.0;
+    let x_range = (x.min - x_half)..(x.max + x_half);
+
+    if config.parameters.len() == 1 {
+        let mut ctx = ChartBuilder::on(&root_area)
+            .set_label_area_size(LabelAreaPosition::Left, 48)
+            .set_label_area_size(LabelAreaPosition::Bottom, 48)
+            .caption("Sweep", ("sans-serif", 32))
+            .build_cartesian_2d(x_range, (min_fitness - 1.0)..(max_fitness + 1.0))
+            .unwrap();
+        ctx.configure_mesh()
+            .x_desc(&x.name)
+            .y_desc("fitness")
+            .draw()
+            .unwrap();
+        ctx.draw_series(
+            rows.iter().filter(|r| !r.failed).map(|r| {
+                TriangleMarker::new((r.params[0], r.fitness), 5, RGBColor(0xf3, 0x70, 0x21))
+            }),
+        )
+        .unwrap();
+        let mut line: Vec<(f64, f64)> = means
+            .iter()
+            .filter_map(|((x, _), f)| f.map(|f| (*x, f)))
+            .collect();
+        line.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        ctx.draw_series(LineSeries::new(
+            line,
+            RGBColor(0x09, 0x61, 0x91).stroke_width(2),
+        ))
+        .unwrap();
+        return;
+    }
+
+    let y = &config.parameters[1];
+    let y_half = config.cell_width(1) / 2.0;
+    let mut ctx = ChartBuilder::on(&root_area)
+        .set_label_area_size(LabelAreaPosition::Left, 48)
+        .set_label_area_size(LabelAreaPosition::Bottom, 48)
+        .caption("Sweep mean fitness", ("sans-serif", 32))
+        .build_cartesian_2d(x_range, (y.min - y_half)..(y.max + y_half))
+        .unwrap();
+    ctx.configure_mesh()
+        .x_desc(&x.name)
+        .y_desc(&y.name)
+        .draw()
+        .unwrap();
+
+    // blue = least fit, red = most fit
+    ctx.draw_series(means.iter().map(|((px, py), fitness)| {
+        let color = match fitness {
+            Some(fitness) => {
+                let t = if max_fitness > min_fitness {
+                    (fitness - min_fitness) / (max_fitness - min_fitness)
+                } else {
+                    1.0
+                };
+                HSLColor(0.66 * (1.0 - t), 0.8, 0.5)
+            }
+            None => HSLColor(0.0, 0.0, 0.7),
+        };
+        Rectangle::new(
+            [(px - x_half, py - y_half), (px + x_half, py + y_half)],
+            color.filled(),
+        )
+    }))
+    .unwrap();
+}
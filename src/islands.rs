@@ -0,0 +1,281 @@
+/// Island-model evolution: several genepools evolving side by side with periodic migration
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use vmmc::Prng;
+
+use crate::engine::{megasteps_run, EvoEngine};
+use crate::fitness::{FitnessFunc, PreparedFitness};
+use crate::nn::Dna;
+use crate::pruning::{elites, prune};
+use crate::schedule::ScheduleState;
+use crate::surrogate::Observations;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Topology {
+    // island i sends migrants to island i + 1
+    #[default]
+    Ring,
+    // every island sends migrants to every other island
+    FullyConnected,
+}
+
+// Per-island settings, falling back to the engine's when unset
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IslandOverride {
+    #[serde(default)]
+    pub fitness_func: Option<FitnessFunc>,
+    #[serde(default)]
+    pub mutation_factor: Option<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IslandConfig {
+    pub num_islands: usize,
+    // generations between migrations
+    pub migration_interval: usize,
+    // top-k genomes sent along each edge of the topology
+    pub num_migrants: usize,
+    #[serde(default)]
+    pub topology: Topology,
+    #[serde(default)]
+    pub overrides: Vec<IslandOverride>,
+}
+
+struct Island {
+    fitness_func: PreparedFitness,
+    schedule_state: ScheduleState,
+    genepool: Vec<(Dna, f64, usize)>,
+    candidates: Vec<Dna>,
+    // surrogate inputs, per island since islands may score with different fitness functions
+    observations: Observations,
+}
+
+impl IslandConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.num_islands == 0 {
+            bail!("Islands needs at least one island");
+        }
+        if self.num_islands < 2 && self.migration_interval > 0 {
+            bail!("Migration needs at least 2 islands, set migration_interval = 0 to disable it");
+        }
+        Ok(())
+    }
+
+    fn destinations(&self, src: usize) -> Vec<usize> {
+        match self.topology {
+            Topology::Ring => vec![(src + 1) % self.num_islands],
+            Topology::FullyConnected => (0..self.num_islands).filter(|&dst| dst != src).collect(),
+        }
+    }
+}
+
+// Copies of the `k` fittest members of a genepool, best first, with the number of evaluated
+// members they were ranked among
+fn emigrants(genepool: &[(Dna, f64, usize)], k: usize) -> (Vec<(Dna, f64, usize)>, usize) {
+    let evaluated = genepool
+        .iter()
+        .filter(|(_, fit, _)| fit.is_finite())
+        .count();
+    let migrants = elites(genepool, k)
+        .into_iter()
+        .map(|idx| genepool[idx].clone())
+        .collect();
+    (migrants, evaluated)
+}
+
+// Islands can use different fitness functions, so a migrant takes the fitness of the
+// destination member at the same relative rank instead of its own
+fn rank_matched_fitnesses(
+    num_migrants: usize,
+    evaluated: usize,
+    dst: &[(Dna, f64, usize)],
+) -> Vec<f64> {
+    let mut scale: Vec<f64> = dst
+        .iter()
+        .map(|(_, fit, _)| *fit)
+        .filter(|fit| fit.is_finite())
+        .collect();
+    scale.sort_by(|a, b| b.total_cmp(a));
+    (0..num_migrants)
+        .map(|rank| {
+            if scale.is_empty() {
+                // nothing evaluated yet, any migrant beats the placeholders
+                return 0.0;
+            }
+            scale[(rank * scale.len() / evaluated.max(1)).min(scale.len() - 1)]
+        })
+        .collect()
+}
+
+impl EvoEngine {
+    fn init_islands(&mut self, config: &IslandConfig) -> Result<Vec<Island>> {
+        (0..config.num_islands)
+            .map(|island_idx| {
+                let island_override = config
+                    .overrides
+                    .get(island_idx)
+                    .cloned()
+                    .unwrap_or_default();
+                let fitness_func = match island_override.fitness_func {
+                    Some(fitness_func) => fitness_func
+                        .prepare(&self.sim_params)
+                        .with_context(|| format!("Island {island_idx} override"))?,
+                    None => self.fitness().clone(),
+                };
+                let mutation_factor = island_override
+                    .mutation_factor
+                    .unwrap_or(self.mutation_factor);
+                let schedule_state = ScheduleState::new(mutation_factor);
+
+                let init_dna = self.init_dna();
+                let candidates: Vec<Dna> = (0..self.generation_size())
+                    .map(|_| self.spawn_child_with(&init_dna, mutation_factor))
+                    .collect();
+                let genepool = candidates[0..self.survivors_per_generation]
+                    .iter()
+                    .map(|c| (c.clone(), f64::NEG_INFINITY, 0))
+                    .collect();
+                Ok(Island {
+                    fitness_func,
+                    schedule_state,
+                    genepool,
+                    candidates,
+                    observations: Observations::default(),
+                })
+            })
+            .collect()
+    }
+
+    fn migrate(&self, config: &IslandConfig, islands: &mut [Island]) {
+        let outgoing: Vec<(Vec<(Dna, f64, usize)>, usize)> = islands
+            .iter()
+            .map(|island| emigrants(&island.genepool, config.num_migrants))
+            .collect();
+        for (src, (migrants, evaluated)) in outgoing.iter().enumerate() {
+            for dst in config.destinations(src) {
+                let ids: Vec<usize> = migrants.iter().map(|(dna, _, _)| dna.id()).collect();
+                log::info!("Migrating {:?} from island {src} to island {dst}", ids);
+                let dnas: Vec<Dna> = migrants.iter().map(|(dna, _, _)| dna.clone()).collect();
+                let fitnesses =
+                    rank_matched_fitnesses(migrants.len(), *evaluated, &islands[dst].genepool);
+                let polygons = migrants.iter().map(|(_, _, p)| *p).collect();
+                prune(
+                    &mut islands[dst].genepool,
+                    &dnas,
+                    fitnesses,
+                    polygons,
+                    self.elitism,
+                );
+            }
+        }
+    }
+
+    pub(crate) fn step_islands_and_save(
+        &mut self,
+        config: IslandConfig,
+        output_dir: &str,
+        rng: &mut Prng,
+    ) -> Result<()> {
+        log::info!(
+            "Evolving {} islands with {:?} migration of {} genomes every {} generations",
+            config.num_islands,
+            config.topology,
+            config.num_migrants,
+            config.migration_interval
+        );
+        let mut islands = self.init_islands(&config)?;
+
+        for gen_idx in 0..self.num_generations {
+            log::info!("Starting generation {gen_idx}: ");
+
+            // 1.) Execute every island's sims on the shared threadpool
+            let start = Instant::now();
+            let gen_dir = format!("./{output_dir}/{:0>3}", gen_idx);
+            let seeds: Vec<u64> = islands.iter().map(|_| rng.random()).collect();
+            let all_children: Vec<_> = islands
+                .par_iter()
+                .enumerate()
+                .map(|(island_idx, island)| {
+                    let mut island_rng = Prng::seed_from_u64(seeds[island_idx]);
+                    let island_dir = format!("{gen_dir}/island{:0>2}", island_idx);
+                    self.step_generation_to(
+                        &island.candidates,
+                        &island.fitness_func,
+                        &mut island_rng,
+                        &island_dir,
+                    )
+                })
+                .collect();
+            log::info!("Generation execution time: {:?}", Instant::now() - start);
+
+            // 2.) Score and prune each island independently
+            let mut all_fitnesses = Vec::new();
+            let mut all_megasteps = 0;
+            for (island_idx, (island, children)) in
+                islands.iter_mut().zip(all_children.iter()).enumerate()
+            {
+                log::info!("Island {island_idx}:");
+                let ids: Vec<usize> = island.candidates.iter().map(|c| c.id()).collect();
+                log::info!("Candidates: {:?}", ids);
+                let fitnesses = self.get_fitnesses(children);
+                let polygons = self.get_polygons(children);
+                self.observe(&mut island.observations, &island.candidates, children);
+                let island_dir = format!("{gen_dir}/island{:0>2}", island_idx);
+                self.record_hall_of_fame(
+                    &island.candidates,
+                    &fitnesses,
+                    gen_idx,
+                    &island_dir,
+                    output_dir,
+                );
+                prune(
+                    &mut island.genepool,
+                    &island.candidates,
+                    fitnesses.clone(),
+                    polygons,
+                    self.elitism,
+                );
+                self.record_genepool(&island.genepool);
+
+                let mutation_factor = island.schedule_state.update(
+                    &self.mutation_schedule,
+                    gen_idx,
+                    self.num_generations,
+                    &fitnesses,
+                );
+                log::info!("Mutation factor for next generation: {mutation_factor}");
+                all_fitnesses.extend_from_slice(&fitnesses);
+                all_megasteps += megasteps_run(children);
+            }
+
+            let genepools: Vec<_> = islands.iter().map(|i| i.genepool.as_slice()).collect();
+            self.record_checkpoint(output_dir, gen_idx, &genepools);
+
+            if self.should_stop(&all_fitnesses, all_megasteps) {
+                break;
+            }
+            if self.is_cancelled() {
+                log::info!("Run cancelled after generation {gen_idx}");
+                break;
+            }
+
+            // 3.) Exchange the fittest genomes between islands
+            if config.migration_interval > 0 && (gen_idx + 1) % config.migration_interval == 0 {
+                self.migrate(&config, &mut islands);
+            }
+
+            // 4.) Refill each island from its genepool
+            for island in islands.iter_mut() {
+                let mutation_factor = island.schedule_state.factor();
+                let surrogate = self.fit_surrogate(&island.observations);
+                island.candidates =
+                    self.spawn_generation(&island.genepool, mutation_factor, surrogate.as_ref());
+            }
+        }
+        Ok(())
+    }
+}
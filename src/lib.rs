@@ -1,4 +1,5 @@
 use anyhow::Result;
+use budget::{BudgetedIter, SimBudget};
 use engine::EvoEngine;
 use fitness::FitnessFunc;
 use nn::fll::FLLConfig;
@@ -6,6 +7,8 @@ use nn::{microstate::MicrostateConfig, timenet::TimeNetConfig};
 // use nn::{fll_temp_only::FLLTempOnlyConfig, l2g_nn::NnConfig};
 use nn::{Dna, LearningStrategy};
 use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex};
 use vmmc::{
     protocol::{ProtocolIter, ProtocolStep, SynthesisProtocol},
     run_vmmc,
@@ -14,11 +17,28 @@ use vmmc::{
 };
 // use rand_core::SeedableRng;
 
+pub mod archive;
+pub mod bayesian;
+pub mod budget;
+pub mod cache;
+pub mod distributed;
 pub mod engine;
+pub mod fidelity;
 pub mod fitness;
+pub mod hall_of_fame;
 pub mod io;
+pub mod islands;
+pub mod local_search;
 pub mod nn;
+pub mod policy_gradient;
 pub mod pruning;
+pub mod reference;
+pub mod schedule;
+pub mod steady_state;
+pub mod stopping;
+pub mod structure;
+pub mod surrogate;
+pub mod sweep;
 
 impl EvoEngine {
     /// Default dna currently set to microstate
@@ -70,16 +90,34 @@ impl Default for EvoEngine {
             sim_params,
             learning_strategy: LearningStrategy::MicroState,
             fitness_func: FitnessFunc::Unitcell("4.4.4.4".to_string()),
+            optimizer: Default::default(),
             init_protocol,
             mutation_factor: 0.5,
+            mutation_schedule: Default::default(),
+            stopping: Default::default(),
+            fault_tolerance: Default::default(),
+            protocol_penalty: Default::default(),
+            sim_budget: Default::default(),
+            distributed: None,
+            cache_fitness: false,
+            multi_fidelity: None,
+            surrogate: None,
             // init_dna,
             seed,
             num_generations,
             children_per_survivor,
             survivors_per_generation,
+            elitism: 0,
+            hall_of_fame_size: 10,
             child_ctr: 0,
             history: Vec::new(),
             fitnesses: Vec::new(),
+            schedule_state: Default::default(),
+            hall_of_fame: Default::default(),
+            stopping_state: Default::default(),
+            cancel: Default::default(),
+            cache: None,
+            prepared_fitness: None,
         }
     }
 }
@@ -95,6 +133,31 @@ pub fn run_fresh_vmmc(
     Ok((proto, vmmc))
 }
 
+// Like `run_fresh_vmmc`, but fails with an `Interrupt` if the budget runs out or `cancel` is set.
+// The megasteps simulated are added to `simulated` even if the run fails.
+pub fn run_fresh_vmmc_with_budget(
+    sim_params: &SimParams,
+    protocol_iter: Box<dyn ProtocolIter>,
+    budget: &SimBudget,
+    cancel: Arc<AtomicBool>,
+    simulated: Arc<AtomicUsize>,
+    rng: &mut Prng,
+) -> Result<(Vec<ProtocolStep>, Vmmc)> {
+    let interrupt = Arc::new(Mutex::new(None));
+    let protocol_iter = BudgetedIter::new(
+        protocol_iter,
+        budget.clone(),
+        cancel,
+        interrupt.clone(),
+        simulated,
+    );
+    let result = run_fresh_vmmc(sim_params, Box::new(protocol_iter), rng)?;
+    match interrupt.lock().unwrap().take() {
+        Some(interrupt) => Err(interrupt.into()),
+        None => Ok(result),
+    }
+}
+
 pub fn run_fresh_vmmc_to_console(
     sim_params: &SimParams,
     protocol_iter: Box<dyn ProtocolIter>,
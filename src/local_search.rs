@@ -0,0 +1,162 @@
+/// Single-trajectory baselines: first-improvement hill climbing and simulated annealing
+use std::slice;
+use std::time::Instant;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use vmmc::Prng;
+
+use crate::engine::{megasteps_run, EvoEngine};
+use crate::nn::Dna;
+use crate::schedule::ScheduleState;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Cooling {
+    // T = initial * rate^generation
+    Exponential { initial: f64, rate: f64 },
+    // from `initial` at the first generation down to `last` at the final one
+    Linear { initial: f64, last: f64 },
+    // T = initial / ln(generation + e)
+    Logarithmic { initial: f64 },
+}
+
+impl Cooling {
+    pub fn temperature(&self, gen_idx: usize, num_generations: usize) -> f64 {
+        match self {
+            Cooling::Exponential { initial, rate } => initial * rate.powi(gen_idx as i32),
+            Cooling::Linear { initial, last } => {
+                let progress = gen_idx as f64 / num_generations.saturating_sub(1).max(1) as f64;
+                initial + (last - initial) * progress
+            }
+            Cooling::Logarithmic { initial } => {
+                initial / (gen_idx as f64 + std::f64::consts::E).ln()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HillClimbConfig {
+    // mutants of the current genome simulated in parallel per generation,
+    // defaults to the generation size
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnealingConfig {
+    pub cooling: Cooling,
+    // mutants of the current genome simulated in parallel per generation,
+    // defaults to the generation size
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+}
+
+// Metropolis criterion for a maximised fitness, plain improvement without a temperature
+fn accept(fitness: f64, current: f64, temperature: Option<f64>, rng: &mut Prng) -> bool {
+    if fitness > current {
+        return true;
+    }
+    match temperature {
+        Some(t) if t > 0.0 => rng.random::<f64>() < ((fitness - current) / t).exp(),
+        _ => false,
+    }
+}
+
+impl EvoEngine {
+    pub(crate) fn step_hill_climbing_and_save(
+        &mut self,
+        config: HillClimbConfig,
+        output_dir: &str,
+        rng: &mut Prng,
+    ) {
+        self.step_local_search_and_save(None, config.batch_size, output_dir, rng);
+    }
+
+    pub(crate) fn step_annealing_and_save(
+        &mut self,
+        config: AnnealingConfig,
+        output_dir: &str,
+        rng: &mut Prng,
+    ) {
+        self.step_local_search_and_save(Some(&config.cooling), config.batch_size, output_dir, rng);
+    }
+
+    // Each generation simulates a batch of mutants of the current genome and moves to the
+    // first one, in candidate order, that passes the acceptance test. The batch only keeps
+    // the threadpool busy, the trajectory is the same as proposing mutants one at a time.
+    fn step_local_search_and_save(
+        &mut self,
+        cooling: Option<&Cooling>,
+        batch_size: Option<usize>,
+        output_dir: &str,
+        rng: &mut Prng,
+    ) {
+        let batch_size = batch_size.unwrap_or(self.generation_size()).max(1);
+        log::info!("Local search with {batch_size} proposals per generation\n");
+        self.schedule_state = ScheduleState::new(self.mutation_factor);
+        let mut current = (self.init_dna(), f64::NEG_INFINITY, 0);
+
+        for gen_idx in 0..self.num_generations {
+            log::info!("Starting generation {gen_idx}: ");
+            let candidates: Vec<Dna> = (0..batch_size)
+                .map(|_| self.spawn_child(&current.0))
+                .collect();
+            let ids: Vec<usize> = candidates.iter().map(|c| c.id()).collect();
+            log::info!("Candidates: {:?}", ids);
+
+            let start = Instant::now();
+            let gen_dir = format!("./{output_dir}/{:0>3}", gen_idx);
+            let children = self.step_generation_to(&candidates, self.fitness(), rng, &gen_dir);
+            log::info!("Generation execution time: {:?}", Instant::now() - start);
+
+            let fitnesses = self.get_fitnesses(&children);
+            let polygons = self.get_polygons(&children);
+            self.record_hall_of_fame(&candidates, &fitnesses, gen_idx, &gen_dir, output_dir);
+
+            let temperature = cooling.map(|c| c.temperature(gen_idx, self.num_generations));
+            if let Some(t) = temperature {
+                log::info!("Temperature: {t}");
+            }
+            // failed and eliminated children are never accepted
+            let accepted = (0..candidates.len()).find(|&idx| {
+                children[idx].fitness().is_some()
+                    && accept(fitnesses[idx], current.1, temperature, rng)
+            });
+            match accepted {
+                Some(idx) => {
+                    log::info!(
+                        "Moved to {} (fitness = {}, was {})",
+                        candidates[idx].id(),
+                        fitnesses[idx],
+                        current.1
+                    );
+                    current = (candidates[idx].clone(), fitnesses[idx], polygons[idx]);
+                }
+                None => log::info!(
+                    "No proposal accepted, staying at {} (fitness = {})",
+                    current.0.id(),
+                    current.1
+                ),
+            }
+            self.record_genepool(slice::from_ref(&current));
+            self.record_checkpoint(output_dir, gen_idx, &[slice::from_ref(&current)]);
+
+            let mutation_factor = self.schedule_state.update(
+                &self.mutation_schedule,
+                gen_idx,
+                self.num_generations,
+                &fitnesses,
+            );
+            log::info!("Mutation factor for next generation: {mutation_factor}");
+
+            if self.should_stop(&fitnesses, megasteps_run(&children)) {
+                break;
+            }
+            if self.is_cancelled() {
+                log::info!("Run cancelled after generation {gen_idx}");
+                break;
+            }
+        }
+    }
+}
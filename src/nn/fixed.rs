@@ -0,0 +1,70 @@
+/// Explicit per-megastep protocol, proposed directly by an optimizer rather than a network
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use vmmc::protocol::{ProtocolIter, ProtocolStep, SynthesisProtocol};
+
+use crate::nn::fll::StaticMegastepIter;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FixedProtocol {
+    chemical_potential: Vec<f64>,
+    interaction_energy: Vec<f64>,
+}
+
+impl FixedProtocol {
+    pub fn flat(chemical_potential: f64, interaction_energy: f64, num_megasteps: usize) -> Self {
+        Self {
+            chemical_potential: vec![chemical_potential; num_megasteps],
+            interaction_energy: vec![interaction_energy; num_megasteps],
+        }
+    }
+
+    // Piecewise linear from the start of `proto`, same parameterisation as the FLL outputs.
+    // The first `proto.len() % num_phases` phases are one megastep longer, so the protocol
+    // keeps the length of `proto`.
+    pub fn from_slopes(
+        proto: &SynthesisProtocol,
+        epsilon_slopes: &[f64],
+        mu_slopes: &[f64],
+    ) -> Result<Self> {
+        let num_phases = epsilon_slopes.len();
+        if num_phases == 0 {
+            bail!("A piecewise linear protocol needs at least one phase");
+        }
+        if mu_slopes.len() != num_phases {
+            bail!(
+                "Got {num_phases} epsilon slopes but {} mu slopes",
+                mu_slopes.len()
+            );
+        }
+        let mut epsilon = proto.interaction_energy(0);
+        let mut mu = proto.chemical_potential(0);
+
+        let mut chemical_potential = Vec::new();
+        let mut interaction_energy = Vec::new();
+        for phase in 0..num_phases {
+            let phase_len =
+                proto.len() / num_phases + usize::from(phase < proto.len() % num_phases);
+            for _ in 0..phase_len {
+                chemical_potential.push(mu.clamp(-20.0, 20.0));
+                interaction_energy.push(epsilon.clamp(0.0, 20.0));
+                epsilon += epsilon_slopes[phase] / phase_len as f64;
+                mu += mu_slopes[phase] / phase_len as f64;
+            }
+        }
+        Ok(Self {
+            chemical_potential,
+            interaction_energy,
+        })
+    }
+
+    pub fn proto_iter(&self) -> impl ProtocolIter {
+        let steps = self
+            .chemical_potential
+            .iter()
+            .zip(self.interaction_energy.iter())
+            .map(|(mu, epsilon)| ProtocolStep::new(*mu, *epsilon))
+            .collect();
+        StaticMegastepIter::new(steps)
+    }
+}
@@ -33,6 +33,10 @@ impl FLLConfig {
         }
     }
 
+    pub fn set_mutation_factor(&mut self, mutation_factor: f32) {
+        self.mutation_factor = mutation_factor;
+    }
+
     pub fn mutate(&mut self) {
         let mut weights = self.nn.get_weights();
         for w in &mut weights {
@@ -76,6 +80,12 @@ pub struct StaticMegastepIter {
     t: usize,
 }
 
+impl StaticMegastepIter {
+    pub fn new(inner: Vec<ProtocolStep>) -> Self {
+        Self { inner, t: 0 }
+    }
+}
+
 impl ProtocolIter for StaticMegastepIter {
     fn next(&mut self, _vmmc: &Vmmc) -> Option<ProtocolStep> {
         if self.t >= self.inner.len() {
@@ -1,8 +1,12 @@
+use std::sync::{Arc, Mutex};
+
+use rand::{Rng, SeedableRng};
 use runnt::initialization::InitializationType;
 /// Implement microstate NN + TimeNet from original paper
 use runnt::{activation::ActivationType, nn::NN, regularization::Regularization};
 use serde::{Deserialize, Serialize};
 use vmmc::morphology::Morphology;
+use vmmc::Prng;
 use vmmc::{
     polygons::calc_bond_distribution,
     protocol::{ProtocolIter, ProtocolStep, SynthesisProtocol},
@@ -47,6 +51,10 @@ impl MicrostateConfig {
         }
     }
 
+    pub fn set_mutation_factor(&mut self, mutation_factor: f32) {
+        self.mutation_factor = mutation_factor;
+    }
+
     // Reusable across architectures
     pub fn mutate(&mut self) {
         let mut weights = self.nn.get_weights();
@@ -59,6 +67,76 @@ impl MicrostateConfig {
     pub fn proto_iter(&self, proto: &SynthesisProtocol) -> impl ProtocolIter {
         MicroStateIter::new(self.nn.clone(), proto)
     }
+
+    // Like `proto_iter`, but every output gets Gaussian noise of std `sigma` and each
+    // (inputs, action) is appended to `trajectory`
+    pub fn exploring_iter(
+        &self,
+        proto: &SynthesisProtocol,
+        sigma: f32,
+        seed: u64,
+        trajectory: Arc<Mutex<Vec<Transition>>>,
+    ) -> impl ProtocolIter {
+        let mut iter = MicroStateIter::new(self.nn.clone(), proto);
+        iter.exploration = Some(Exploration {
+            sigma,
+            rng: Prng::seed_from_u64(seed),
+            trajectory,
+        });
+        iter
+    }
+
+    // One REINFORCE step for a Gaussian policy: moving the regression target from the mean
+    // towards the taken action, scaled by the advantage, follows the gradient of
+    // advantage * log pi(action | inputs). The mean is recomputed before every fit, since
+    // the earlier fits of this step have already moved the network.
+    pub fn reinforce(&mut self, trajectory: &[Transition], advantage: f32, step_size: f32) {
+        for transition in trajectory {
+            let mean = self.nn.forward(&transition.inputs);
+            let target: Vec<f32> = mean
+                .iter()
+                .zip(transition.action.iter())
+                .map(|(mean, action)| mean + step_size * advantage * (action - mean))
+                .collect();
+            self.nn.fit_one(&transition.inputs, &target);
+        }
+    }
+}
+
+/// One controller decision taken while exploring
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub inputs: Vec<f32>,
+    pub action: Vec<f32>,
+}
+
+struct Exploration {
+    sigma: f32,
+    rng: Prng,
+    trajectory: Arc<Mutex<Vec<Transition>>>,
+}
+
+impl Exploration {
+    // Box-Muller
+    fn gaussian(&mut self) -> f32 {
+        let u1 = 1.0 - self.rng.random::<f32>();
+        let u2 = self.rng.random::<f32>();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+    }
+
+    fn act(&mut self, nn: &NN, inputs: Vec<f32>) -> (f64, f64) {
+        let mean = nn.forward(&inputs);
+        let action: Vec<f32> = mean
+            .iter()
+            .map(|m| m + self.sigma * self.gaussian())
+            .collect();
+        let outputs = (action[1] as f64, action[0] as f64);
+        self.trajectory
+            .lock()
+            .unwrap()
+            .push(Transition { inputs, action });
+        outputs
+    }
 }
 
 fn format_inputs_for_one(patch_distr: &[usize]) -> Vec<f32> {
@@ -88,6 +166,7 @@ pub struct MicroStateIter {
     nn: NN,
     t: f64,
     protocol: SynthesisProtocol,
+    exploration: Option<Exploration>,
 }
 
 impl MicroStateIter {
@@ -96,6 +175,7 @@ impl MicroStateIter {
             nn,
             t: 0.0,
             protocol: protocol.clone(),
+            exploration: None,
         }
     }
 
@@ -119,7 +199,11 @@ impl ProtocolIter for MicroStateIter {
 
         let patch_distrs = calc_bond_distribution(vmmc);
 
-        let (epsilon, mu) = self.eval(&patch_distrs, self.t as f32);
+        let t = self.t as f32;
+        let (epsilon, mu) = match &mut self.exploration {
+            Some(exploration) => exploration.act(&self.nn, format_inputs(&patch_distrs, t)),
+            None => self.eval(&patch_distrs, t),
+        };
 
         let orig_epsilon = self
             .protocol
@@ -1,8 +1,10 @@
+pub mod fixed;
 pub mod fll;
 pub mod microstate;
 pub mod timenet;
 
 use crate::nn::timenet::TimeNetConfig;
+use fixed::FixedProtocol;
 use fll::FLLConfig;
 use microstate::MicrostateConfig;
 use serde::{Deserialize, Serialize};
@@ -20,6 +22,7 @@ enum DnaInner {
     TimeNet(TimeNetConfig, SynthesisProtocol),
     Fll(FLLConfig, SynthesisProtocol),
     MicroState(MicrostateConfig, SynthesisProtocol),
+    Fixed(FixedProtocol),
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -48,6 +51,7 @@ impl Dna {
             TimeNet(nn, proto) => Box::new(nn.proto_iter(proto)),
             Fll(nn, proto) => Box::new(nn.proto_iter(proto)),
             MicroState(config, proto) => Box::new(config.proto_iter(proto)),
+            Fixed(protocol) => Box::new(protocol.proto_iter()),
         }
     }
 
@@ -57,6 +61,7 @@ impl Dna {
             TimeNet(..) => "Time Network (Steve's code)",
             Fll(..) => "FLL (fixed-length linear)",
             MicroState(..) => "Microstate + Time Network (Steve's code)",
+            Fixed(..) => "Fixed protocol",
         }
     }
 
@@ -72,6 +77,64 @@ impl Dna {
         Dna::new(0, DnaInner::MicroState(config, proto))
     }
 
+    pub fn fresh_fixed(protocol: FixedProtocol) -> Self {
+        Dna::new(0, DnaInner::Fixed(protocol))
+    }
+
+    pub(crate) fn with_id(mut self, id: usize) -> Self {
+        self.id = id;
+        self
+    }
+
+    // The Microstate controller and its base protocol, for trainers that update it directly
+    pub fn microstate(&self) -> Option<(&MicrostateConfig, &SynthesisProtocol)> {
+        match &self.inner {
+            DnaInner::MicroState(config, proto) => Some((config, proto)),
+            _ => None,
+        }
+    }
+
+    pub fn microstate_mut(&mut self) -> Option<&mut MicrostateConfig> {
+        match &mut self.inner {
+            DnaInner::MicroState(config, _) => Some(config),
+            _ => None,
+        }
+    }
+
+    pub fn set_mutation_factor(&mut self, mutation_factor: f32) {
+        use DnaInner::*;
+        match &mut self.inner {
+            TimeNet(nn, ..) => nn.set_mutation_factor(mutation_factor as f64),
+            Fll(nn, ..) => nn.set_mutation_factor(mutation_factor),
+            MicroState(nn, ..) => nn.set_mutation_factor(mutation_factor),
+            Fixed(..) => {}
+        }
+    }
+
+    // Serialized model and protocol without the id, so identical genomes hash the same
+    pub(crate) fn genome_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&self.inner)
+    }
+
+    // Every number in the genome in serialization order, used as surrogate-model input
+    pub fn genome_features(&self) -> Vec<f64> {
+        fn collect(value: &serde_json::Value, out: &mut Vec<f64>) {
+            use serde_json::Value::*;
+            match value {
+                Number(n) => out.push(n.as_f64().unwrap_or(0.0)),
+                Array(values) => values.iter().for_each(|v| collect(v, out)),
+                Object(map) => map.values().for_each(|v| collect(v, out)),
+                _ => {}
+            }
+        }
+
+        let mut features = Vec::new();
+        if let Ok(value) = serde_json::to_value(&self.inner) {
+            collect(&value, &mut features);
+        }
+        features
+    }
+
     pub fn mutate(&mut self, new_id: usize) {
         use DnaInner::*;
         match &mut self.inner {
@@ -80,6 +143,8 @@ impl Dna {
             }
             Fll(nn, ..) => nn.mutate(),
             MicroState(nn, ..) => nn.mutate(),
+            // proposed by an optimizer, never mutated
+            Fixed(..) => {}
         }
         self.id = new_id;
     }
@@ -37,6 +37,10 @@ impl TimeNetConfig {
         self.child_id
     }
 
+    pub fn set_mutation_factor(&mut self, mutation_factor: f64) {
+        self.mutation_factor = mutation_factor
+    }
+
     pub fn proto_iter(&self, proto: &SynthesisProtocol) -> impl ProtocolIter {
         let nn = NueralNet::from_config(self);
         nn.current_protocol(proto)
@@ -0,0 +1,189 @@
+/// REINFORCE training of the Microstate controller, as a gradient-based alternative to evolution
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use vmmc::Prng;
+
+use crate::engine::{megasteps_run, Evaluation, EvoEngine};
+use crate::io::write_policy_toml;
+use crate::nn::microstate::Transition;
+use crate::nn::Dna;
+
+fn default_sigma() -> f32 {
+    0.5
+}
+
+fn default_step_size() -> f32 {
+    1.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyGradientConfig {
+    // std of the Gaussian exploration noise on both controller outputs
+    #[serde(default = "default_sigma")]
+    pub sigma: f32,
+    // episodes per update, defaults to the generation size
+    #[serde(default)]
+    pub episodes_per_update: Option<usize>,
+    // scales the advantage-weighted regression targets, on top of the network learning rate
+    #[serde(default = "default_step_size")]
+    pub step_size: f32,
+}
+
+impl Default for PolicyGradientConfig {
+    fn default() -> Self {
+        Self {
+            sigma: default_sigma(),
+            episodes_per_update: None,
+            step_size: default_step_size(),
+        }
+    }
+}
+
+impl EvoEngine {
+    // Simulate one noisy rollout of `dna` like any other child, returning the evaluation and
+    // the decisions taken on the attempt that finished
+    fn run_episode(
+        &self,
+        dna: &Dna,
+        config: &PolicyGradientConfig,
+        seed: u64,
+        p_str: &str,
+    ) -> (Evaluation, Vec<Transition>) {
+        let (controller, proto) = dna
+            .microstate()
+            .expect("checked by step_policy_gradient_and_save");
+        let trajectory = Arc::new(Mutex::new(Vec::new()));
+        let evaluation = self.run_attempts(dna, self.fitness(), seed, p_str, |rng| {
+            // a retry explores afresh
+            trajectory.lock().unwrap().clear();
+            Box::new(controller.exploring_iter(
+                proto,
+                config.sigma,
+                rng.random(),
+                trajectory.clone(),
+            ))
+        });
+        let trajectory = std::mem::take(&mut *trajectory.lock().unwrap());
+        (evaluation, trajectory)
+    }
+
+    // Every generation runs `episodes_per_update` rollouts of the current policy in parallel
+    // and takes one REINFORCE step with the terminal fitness as reward, normalised across
+    // the batch. Failed and cancelled rollouts are left out of the update. The final policy is
+    // written to `{output_dir}/policy.toml`, also when the run is cancelled.
+    pub(crate) fn step_policy_gradient_and_save(
+        &mut self,
+        config: PolicyGradientConfig,
+        output_dir: &str,
+        rng: &mut Prng,
+    ) -> Result<()> {
+        let mut policy = self.init_dna();
+        if policy.microstate().is_none() {
+            bail!(
+                "Optimizer::PolicyGradient requires learning_strategy = \"MicroState\", got {:?}",
+                self.learning_strategy
+            );
+        }
+        let num_episodes = config
+            .episodes_per_update
+            .unwrap_or(self.generation_size())
+            .max(1);
+        log::info!(
+            "Policy gradient with {num_episodes} episodes per update, sigma = {}\n",
+            config.sigma
+        );
+
+        for gen_idx in 0..self.num_generations {
+            log::info!("Starting generation {gen_idx}: ");
+            self.history.push((policy.id(), self.child_ctr));
+            policy = policy.with_id(self.child_ctr);
+            self.child_ctr += 1;
+            log::info!("Policy: {}", policy.id());
+
+            let start = Instant::now();
+            let gen_dir = format!("./{output_dir}/{:0>3}", gen_idx);
+            let seeds: Vec<u64> = (0..num_episodes).map(|_| rng.random()).collect();
+            let episodes: Vec<(Evaluation, Vec<Transition>)> = seeds
+                .par_iter()
+                .enumerate()
+                .map(|(idx, seed)| {
+                    let p_str = format!("./{gen_dir}/{:0>3}", idx);
+                    self.run_episode(&policy, &config, *seed, &p_str)
+                })
+                .collect();
+            log::info!("Generation execution time: {:?}", Instant::now() - start);
+
+            let mut trajectories = Vec::new();
+            let children: Vec<Evaluation> = episodes
+                .into_iter()
+                .map(|(child, trajectory)| {
+                    if let Some(fitness) = child.fitness() {
+                        trajectories.push((fitness, trajectory));
+                    }
+                    child
+                })
+                .collect();
+            let fitnesses = self.get_fitnesses(&children);
+            let polygons = self.get_polygons(&children);
+            if let Some(best) = (0..children.len())
+                .filter(|&idx| fitnesses[idx].is_finite())
+                .max_by(|&a, &b| fitnesses[a].total_cmp(&fitnesses[b]))
+            {
+                log::info!(
+                    "Best episode {best}: fitness = {}, polygons = {}",
+                    fitnesses[best],
+                    polygons[best]
+                );
+            }
+            let candidates = vec![policy.clone(); children.len()];
+            self.record_hall_of_fame(&candidates, &fitnesses, gen_idx, &gen_dir, output_dir);
+
+            // normalised advantages, no update if every episode scored the same
+            let rewards: Vec<f64> = trajectories.iter().map(|(r, _)| *r).collect();
+            let mean = rewards.iter().sum::<f64>() / rewards.len().max(1) as f64;
+            let std = (rewards.iter().map(|r| (r - mean).powi(2)).sum::<f64>()
+                / rewards.len().max(1) as f64)
+                .sqrt();
+            if std > 0.0 {
+                let controller = policy.microstate_mut().unwrap();
+                for (reward, trajectory) in &trajectories {
+                    let advantage = ((reward - mean) / std) as f32;
+                    controller.reinforce(trajectory, advantage, config.step_size);
+                }
+                log::info!("Updated policy from {} episodes", trajectories.len());
+            } else {
+                log::info!("No reward signal, policy unchanged");
+            }
+            // the updated policy, with the mean reward of the episodes it was trained on
+            let checkpoint_fitness = if trajectories.is_empty() {
+                f64::NEG_INFINITY
+            } else {
+                mean
+            };
+            let mean_polygons = polygons.iter().sum::<usize>() / polygons.len().max(1);
+            let current = (policy.clone(), checkpoint_fitness, mean_polygons);
+            self.record_checkpoint(output_dir, gen_idx, &[slice::from_ref(&current)]);
+
+            if self.should_stop(&fitnesses, megasteps_run(&children)) {
+                break;
+            }
+            if self.is_cancelled() {
+                log::info!("Run cancelled after generation {gen_idx}");
+                break;
+            }
+        }
+
+        let path = format!("{output_dir}/policy.toml");
+        log::info!("Writing trained policy {} to {path}", policy.id());
+        if let Err(err) = write_policy_toml(&policy, &path) {
+            log::warn!("Unable to write policy: {err:#}");
+        }
+        Ok(())
+    }
+}
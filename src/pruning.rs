@@ -1,31 +1,89 @@
 use crate::nn::Dna;
 
-fn get_index_of_least_fit(genepool: &[(Dna, f64, usize)]) -> (usize, f64) {
-    let mut lowest = genepool[0].1;
-    let mut index = 0;
+fn get_index_of_least_fit(
+    genepool: &[(Dna, f64, usize)],
+    protected: &[usize],
+) -> Option<(usize, f64)> {
+    let mut least_fit: Option<(usize, f64)> = None;
     for (idx, (_, v, _)) in genepool.iter().enumerate() {
-        if v < &lowest {
-            lowest = *v;
-            index = idx;
+        if protected.contains(&idx) {
+            continue;
+        }
+        match least_fit {
+            Some((_, lowest)) if *v >= lowest => {}
+            _ => least_fit = Some((idx, *v)),
         }
     }
-    (index, lowest)
+    least_fit
+}
+
+// Indices of the `k` fittest genepool members that have actually been evaluated
+pub fn elites(genepool: &[(Dna, f64, usize)], k: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..genepool.len())
+        .filter(|&idx| genepool[idx].1.is_finite())
+        .collect();
+    indices.sort_by(|&a, &b| genepool[b].1.total_cmp(&genepool[a].1));
+    indices.truncate(k);
+    indices
 }
 
 // Note: this function is implemented assuming that computing fitness is cheap
 // pretty easy to optimize if that isn't the case
+// The `elitism` fittest members of the genepool are never replaced. Candidates that are
+// already in the genepool (the initial pool, re-evaluated elites) take their new score
+// in place instead of being inserted twice, unless the re-run failed, was cancelled or
+// eliminated: a member keeps its last real score rather than dropping to -inf.
 pub fn prune(
     genepool: &mut [(Dna, f64, usize)],
     candidates: &[Dna],
     fitnesses: Vec<f64>,
     polygons: Vec<usize>,
+    elitism: usize,
 ) {
+    let protected = elites(genepool, elitism);
     for (idx, dna) in candidates.iter().enumerate() {
         let fitness = fitnesses[idx];
         let polygon = polygons[idx];
-        let (index, lowest_fit) = get_index_of_least_fit(genepool);
+        if let Some(entry) = genepool.iter_mut().find(|(g, _, _)| g.id() == dna.id()) {
+            if fitness.is_finite() {
+                entry.1 = fitness;
+                entry.2 = polygon;
+            }
+            continue;
+        }
+        let Some((index, lowest_fit)) = get_index_of_least_fit(genepool, &protected) else {
+            return;
+        };
         if fitness > lowest_fit {
             genepool[index] = (dna.clone(), fitness, polygon)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nn::fixed::FixedProtocol;
+
+    fn dna(id: usize) -> Dna {
+        Dna::fresh_fixed(FixedProtocol::flat(0.0, 10.0, 1)).with_id(id)
+    }
+
+    #[test]
+    fn failed_elite_rerun_keeps_its_score() {
+        let mut genepool = vec![(dna(0), 5.0, 3), (dna(1), 1.0, 2)];
+        // the elite's re-run failed, the newcomer beats the other member
+        let candidates = [dna(0), dna(2)];
+        prune(
+            &mut genepool,
+            &candidates,
+            vec![f64::NEG_INFINITY, 2.0],
+            vec![0, 4],
+            1,
+        );
+        assert_eq!(genepool[0].0.id(), 0);
+        assert_eq!((genepool[0].1, genepool[0].2), (5.0, 3));
+        assert_eq!(genepool[1].0.id(), 2);
+        assert_eq!(elites(&genepool, 1), vec![0]);
+    }
+}
@@ -0,0 +1,190 @@
+/// Reference particle configurations and rotation- and translation-invariant similarity to them
+use std::f64::consts::PI;
+
+use anyhow::{bail, Context, Result};
+use vmmc::polygons::calc_polygon_distribution;
+use vmmc::vmmc::Vmmc;
+
+use crate::structure::{box_size, curve_similarity, min_image, positions, CorrelationFunction};
+
+const ANGLE_BINS: usize = 36;
+const RDF_BINS: usize = 50;
+
+/// g(r) compared by `Reference::similarity`
+pub fn reference_rdf(r_max: f64) -> CorrelationFunction {
+    CorrelationFunction::RadialDistribution {
+        r_max,
+        bins: RDF_BINS,
+    }
+}
+
+/// Particle positions in a periodic box, with the polygon distribution if it is known.
+/// Stored as text: `# box <width> <height>`, optionally `# polygons <counts>...`, then one
+/// `x y` line per particle.
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub box_size: (f64, f64),
+    pub positions: Vec<(f64, f64)>,
+    pub polygons: Option<Vec<usize>>,
+}
+
+/// What `Reference::similarity` compares: the normalised bond-angle histogram, g(r) and
+/// normalised polygon distribution of a configuration
+pub struct Features {
+    bond_angles: Vec<f64>,
+    rdf: Vec<f64>,
+    polygons: Option<Vec<f64>>,
+}
+
+impl Reference {
+    pub fn from_vmmc(vmmc: &Vmmc) -> Self {
+        Self {
+            box_size: box_size(vmmc),
+            positions: positions(vmmc),
+            polygons: Some(calc_polygon_distribution(vmmc, 12)),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading reference configuration {path}"))?;
+        Self::parse(&contents).with_context(|| format!("Parsing reference configuration {path}"))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut box_size = None;
+        let mut polygons = None;
+        let mut positions = Vec::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('#') {
+                let mut cols = header.split_whitespace();
+                match cols.next() {
+                    Some("box") => {
+                        let dims: Vec<f64> = cols.map(str::parse).collect::<Result<_, _>>()?;
+                        match dims.as_slice() {
+                            [w, h] => box_size = Some((*w, *h)),
+                            _ => bail!("Expected \"# box <width> <height>\", got \"{line}\""),
+                        }
+                    }
+                    Some("polygons") => {
+                        polygons = Some(cols.map(str::parse).collect::<Result<_, _>>()?);
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            match cols.as_slice() {
+                [x, y, ..] => positions.push((x.parse()?, y.parse()?)),
+                _ => bail!("Expected \"x y\", got \"{line}\""),
+            }
+        }
+        let Some(box_size) = box_size else {
+            bail!("Missing \"# box <width> <height>\" header");
+        };
+        Ok(Self {
+            box_size,
+            positions,
+            polygons,
+        })
+    }
+
+    pub fn write(&self, pathname: &str) -> Result<()> {
+        let mut out = format!("# box {} {}\n", self.box_size.0, self.box_size.1);
+        if let Some(polygons) = &self.polygons {
+            let counts: Vec<String> = polygons.iter().map(|c| c.to_string()).collect();
+            out.push_str(&format!("# polygons {}\n", counts.join(" ")));
+        }
+        for (x, y) in &self.positions {
+            out.push_str(&format!("{x} {y}\n"));
+        }
+        std::fs::write(pathname, out)?;
+        Ok(())
+    }
+
+    // Directions of the bonds at particle `i`, to every particle closer than `cutoff`
+    fn bond_directions(&self, i: usize, cutoff: f64) -> Vec<f64> {
+        let (w, h) = self.box_size;
+        let a = self.positions[i];
+        self.positions
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .filter_map(|(_, b)| {
+                let (dx, dy) = (min_image(b.0 - a.0, w), min_image(b.1 - a.1, h));
+                (dx * dx + dy * dy < cutoff * cutoff).then(|| dy.atan2(dx))
+            })
+            .collect()
+    }
+
+    // Histogram of angles between pairs of bonds at the same particle, normalised to sum 1
+    fn bond_angle_histogram(&self, cutoff: f64) -> Vec<f64> {
+        let mut hist = vec![0.0; ANGLE_BINS];
+        for i in 0..self.positions.len() {
+            let bonds = self.bond_directions(i, cutoff);
+            for (k, t0) in bonds.iter().enumerate() {
+                for t1 in &bonds[k + 1..] {
+                    let mut angle = (t1 - t0).abs() % (2.0 * PI);
+                    if angle > PI {
+                        angle = 2.0 * PI - angle;
+                    }
+                    let bin = ((angle / PI * ANGLE_BINS as f64) as usize).min(ANGLE_BINS - 1);
+                    hist[bin] += 1.0;
+                }
+            }
+        }
+        normalise(&hist)
+    }
+
+    pub fn features(&self, cutoff: f64, rdf: &CorrelationFunction) -> Features {
+        let polygons = self.polygons.as_ref().map(|p| {
+            let counts: Vec<f64> = p.iter().map(|c| *c as f64).collect();
+            normalise(&counts)
+        });
+        Features {
+            bond_angles: self.bond_angle_histogram(cutoff),
+            rdf: rdf.eval_positions(&self.positions, self.box_size),
+            polygons,
+        }
+    }
+
+    // Mean of the polygon, bond-angle and g(r) similarities of this configuration to a
+    // reference with `features`, each in [0, 1]. The polygon histogram only counts if both
+    // configurations have one. A configuration without a single bond scores 0, an empty
+    // histogram would otherwise still count as half similar.
+    pub fn similarity(&self, other: &Features, cutoff: f64, rdf: &CorrelationFunction) -> f64 {
+        let bonded = (0..self.positions.len()).any(|i| !self.bond_directions(i, cutoff).is_empty());
+        if self.positions.len() < 2 || !bonded {
+            return 0.0;
+        }
+        let own = self.features(cutoff, rdf);
+        let mut scores = vec![
+            histogram_similarity(&own.bond_angles, &other.bond_angles),
+            curve_similarity(&own.rdf, &other.rdf),
+        ];
+        if let (Some(a), Some(b)) = (&own.polygons, &other.polygons) {
+            scores.push(histogram_similarity(a, b));
+        }
+        scores.iter().sum::<f64>() / scores.len() as f64
+    }
+}
+
+fn normalise(hist: &[f64]) -> Vec<f64> {
+    let total: f64 = hist.iter().sum();
+    if total == 0.0 {
+        return hist.to_vec();
+    }
+    hist.iter().map(|h| h / total).collect()
+}
+
+// 1 - half the L1 distance between normalised histograms, padded to the same length
+fn histogram_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let len = a.len().max(b.len());
+    let at = |h: &[f64], i: usize| h.get(i).copied().unwrap_or(0.0);
+    let dist: f64 = (0..len).map(|i| (at(a, i) - at(b, i)).abs()).sum();
+    1.0 - 0.5 * dist
+}
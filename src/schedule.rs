@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+
+/// How the base mutation factor changes as generations go by
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Decay {
+    #[default]
+    Constant,
+    // linearly interpolate to the given factor by the final generation
+    Linear(f32),
+    // multiply the factor by the given rate every generation
+    Exponential(f32),
+}
+
+impl Decay {
+    fn apply(&self, initial: f32, elapsed: usize, total: usize) -> f32 {
+        match self {
+            Decay::Constant => initial,
+            Decay::Linear(final_factor) => {
+                let span = total.saturating_sub(1).max(1) as f32;
+                let t = (elapsed as f32 / span).min(1.0);
+                initial + (final_factor - initial) * t
+            }
+            Decay::Exponential(rate) => initial * rate.powi(elapsed as i32),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StagnationBoost {
+    // generations without an improvement in best fitness before boosting
+    pub patience: usize,
+    // multiplier applied to the decayed factor while stagnating
+    pub boost: f32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MutationSchedule {
+    #[serde(default)]
+    pub decay: Decay,
+    #[serde(default)]
+    pub stagnation: Option<StagnationBoost>,
+    // restart the decay when the spread (max - min) of a generation's
+    // fitnesses falls below this value
+    #[serde(default)]
+    pub diversity_threshold: Option<f64>,
+}
+
+/// Runtime state of a `MutationSchedule`
+#[derive(Debug, Clone, Default)]
+pub struct ScheduleState {
+    initial: f32,
+    factor: f32,
+    best_fitness: Option<f64>,
+    stale_generations: usize,
+    last_reset: usize,
+}
+
+impl ScheduleState {
+    pub fn new(initial: f32) -> Self {
+        Self {
+            initial,
+            factor: initial,
+            ..Default::default()
+        }
+    }
+
+    pub fn factor(&self) -> f32 {
+        self.factor
+    }
+
+    pub fn stale_generations(&self) -> usize {
+        self.stale_generations
+    }
+
+    // Update the effective mutation factor after generation `gen_idx` has been scored
+    pub fn update(
+        &mut self,
+        schedule: &MutationSchedule,
+        gen_idx: usize,
+        num_generations: usize,
+        fitnesses: &[f64],
+    ) -> f32 {
+        let gen_best = fitnesses.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        match self.best_fitness {
+            Some(best) if gen_best <= best => self.stale_generations += 1,
+            _ => {
+                self.best_fitness = Some(gen_best);
+                self.stale_generations = 0;
+            }
+        }
+
+        if let Some(threshold) = schedule.diversity_threshold {
+            // failed children score -inf and say nothing about diversity
+            let measured: Vec<f64> = fitnesses
+                .iter()
+                .cloned()
+                .filter(|f| f.is_finite())
+                .collect();
+            let gen_worst = measured.iter().cloned().fold(f64::INFINITY, f64::min);
+            if measured.len() > 1 && gen_best - gen_worst < threshold {
+                log::info!(
+                    "Diversity collapse (spread = {}), resetting mutation factor",
+                    gen_best - gen_worst
+                );
+                self.last_reset = gen_idx + 1;
+            }
+        }
+
+        let elapsed = (gen_idx + 1).saturating_sub(self.last_reset);
+        let remaining = num_generations.saturating_sub(self.last_reset);
+        let mut factor = schedule.decay.apply(self.initial, elapsed, remaining);
+        if let Some(stagnation) = &schedule.stagnation {
+            if self.stale_generations >= stagnation.patience {
+                factor *= stagnation.boost;
+            }
+        }
+        self.factor = factor;
+        factor
+    }
+}
@@ -0,0 +1,133 @@
+/// Steady-state asynchronous evolution: children are scored and replaced one at a time
+use std::slice;
+use std::sync::mpsc;
+use std::thread;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use vmmc::Prng;
+
+use crate::engine::EvoEngine;
+use crate::nn::Dna;
+use crate::pruning::prune;
+use crate::schedule::ScheduleState;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SteadyStateConfig {
+    // simulations kept in flight, defaults to the generation size
+    #[serde(default)]
+    pub num_workers: Option<usize>,
+    // total children to evaluate, defaults to `num_generations` generations worth
+    #[serde(default)]
+    pub max_evaluations: Option<usize>,
+}
+
+impl EvoEngine {
+    // Children are written to `{output_dir}/child{id}` since there are no generations.
+    // Bookkeeping that is per-generation elsewhere (mutation schedule, stopping criteria,
+    // checkpoints) happens every `generation_size()` evaluations instead.
+    pub(crate) fn step_steady_state_and_save(
+        &mut self,
+        config: SteadyStateConfig,
+        output_dir: &str,
+        rng: &mut Prng,
+    ) {
+        let batch_size = self.generation_size();
+        let num_workers = config.num_workers.unwrap_or(batch_size);
+        let max_evaluations = config
+            .max_evaluations
+            .unwrap_or(self.num_generations * batch_size);
+        let num_batches = max_evaluations.div_ceil(batch_size);
+        log::info!(
+            "Steady-state evolution of {max_evaluations} children on {num_workers} workers\n"
+        );
+
+        self.schedule_state = ScheduleState::new(self.mutation_factor);
+        let mut pending = self.initial_candidates().into_iter();
+        let mut genepool: Vec<(Dna, f64, usize)> = pending.as_slice()
+            [0..self.survivors_per_generation]
+            .iter()
+            .map(|c| (c.clone(), f64::NEG_INFINITY, 0))
+            .collect();
+
+        // workers only need the immutable simulation settings
+        let worker = self.clone();
+        let worker = &worker;
+        let fitness_func = &self.fitness().clone();
+        let (tx, rx) = mpsc::channel();
+
+        let mut started = 0;
+        let mut in_flight = 0;
+        let mut batch = Vec::new();
+        let mut batch_megasteps = 0;
+        let mut batch_idx = 0;
+        let mut stopped = false;
+
+        thread::scope(|s| loop {
+            // 1.) Keep every worker busy with a child of the current genepool
+            while in_flight < num_workers && started < max_evaluations && !stopped {
+                let dna = pending.next().unwrap_or_else(|| {
+                    let parent = genepool[rng.random_range(0..genepool.len())].0.clone();
+                    self.spawn_child(&parent)
+                });
+                let seed: u64 = rng.random();
+                let p_str = format!("./{output_dir}/child{:0>5}", dna.id());
+                let tx = tx.clone();
+                s.spawn(move || {
+                    let child = worker.run_child(&dna, fitness_func, seed, &p_str);
+                    tx.send((dna, p_str, child)).unwrap();
+                });
+                started += 1;
+                in_flight += 1;
+            }
+            if in_flight == 0 {
+                break;
+            }
+
+            // 2.) Score the next child to finish and let it compete for a place in the genepool
+            let (dna, p_str, child) = rx.recv().unwrap();
+            in_flight -= 1;
+            log::info!("Child {} finished", dna.id());
+            let child = slice::from_ref(&child);
+            let fitness = self.get_fitnesses(child)[0];
+            let polygons = self.get_polygons(child)[0];
+            self.hall_of_fame.insert(&dna, fitness, batch_idx, p_str);
+            prune(
+                &mut genepool,
+                slice::from_ref(&dna),
+                vec![fitness],
+                vec![polygons],
+                self.elitism,
+            );
+            batch.push(fitness);
+            batch_megasteps += child[0].megasteps;
+
+            // 3.) Per-generation bookkeeping once a generation's worth of children is in
+            if batch.len() == batch_size || (in_flight == 0 && !batch.is_empty()) {
+                self.record_genepool(&genepool);
+                self.save_hall_of_fame(output_dir);
+                self.record_checkpoint(output_dir, batch_idx, &[genepool.as_slice()]);
+                let mutation_factor = self.schedule_state.update(
+                    &self.mutation_schedule,
+                    batch_idx,
+                    num_batches,
+                    &batch,
+                );
+                log::info!("Mutation factor for next children: {mutation_factor}");
+                if self.should_stop(&batch, batch_megasteps) {
+                    stopped = true;
+                }
+                batch.clear();
+                batch_megasteps = 0;
+                batch_idx += 1;
+            }
+            // running children are interrupted by the same flag, the genepool is saved now in
+            // case they take a while to wind down
+            if self.is_cancelled() && !stopped {
+                log::info!("Run cancelled, waiting for {in_flight} running children");
+                self.record_checkpoint(output_dir, batch_idx, &[genepool.as_slice()]);
+                stopped = true;
+            }
+        });
+    }
+}
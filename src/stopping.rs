@@ -0,0 +1,119 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Criteria for ending a run before `num_generations` is reached
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoppingCriteria {
+    // stop once any child reaches this fitness
+    #[serde(default)]
+    pub target_fitness: Option<f64>,
+    // stop after this many generations without improving the best fitness
+    #[serde(default)]
+    pub patience: Option<usize>,
+    // stop before starting a generation that would overrun this many seconds
+    #[serde(default)]
+    pub max_wall_time_secs: Option<u64>,
+    // stop once this many megasteps have been simulated across all children
+    #[serde(default)]
+    pub max_megasteps: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub enum StopReason {
+    TargetFitness(f64),
+    NoImprovement(usize),
+    WallClock(Duration),
+    MegastepBudget(usize),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::TargetFitness(fit) => write!(f, "target fitness reached (fitness = {fit})"),
+            StopReason::NoImprovement(gens) => {
+                write!(f, "no improvement for {gens} generations")
+            }
+            StopReason::WallClock(elapsed) => {
+                write!(f, "wall-clock budget exhausted after {elapsed:?}")
+            }
+            StopReason::MegastepBudget(megasteps) => {
+                write!(f, "megastep budget exhausted after {megasteps} megasteps")
+            }
+        }
+    }
+}
+
+/// Runtime progress tracked against a `StoppingCriteria`
+#[derive(Debug, Clone, Default)]
+pub struct StoppingState {
+    start: Option<Instant>,
+    last_generation: Option<Instant>,
+    longest_generation: Duration,
+    best_fitness: Option<f64>,
+    stale_generations: usize,
+    megasteps: usize,
+}
+
+impl StoppingState {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: Some(now),
+            last_generation: Some(now),
+            ..Default::default()
+        }
+    }
+
+    pub fn megasteps(&self) -> usize {
+        self.megasteps
+    }
+
+    // Record a finished generation and check whether the run should stop
+    pub fn update(
+        &mut self,
+        criteria: &StoppingCriteria,
+        fitnesses: &[f64],
+        megasteps: usize,
+    ) -> Option<StopReason> {
+        let now = Instant::now();
+        let start = *self.start.get_or_insert(now);
+        let last = self.last_generation.replace(now).unwrap_or(start);
+        self.longest_generation = self.longest_generation.max(now - last);
+        self.megasteps += megasteps;
+
+        let gen_best = fitnesses.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        match self.best_fitness {
+            Some(best) if gen_best <= best => self.stale_generations += 1,
+            _ => {
+                self.best_fitness = Some(gen_best);
+                self.stale_generations = 0;
+            }
+        }
+
+        if let Some(target) = criteria.target_fitness {
+            if gen_best >= target {
+                return Some(StopReason::TargetFitness(gen_best));
+            }
+        }
+        if let Some(patience) = criteria.patience {
+            if self.stale_generations >= patience {
+                return Some(StopReason::NoImprovement(self.stale_generations));
+            }
+        }
+        if let Some(secs) = criteria.max_wall_time_secs {
+            // assume the next generation takes as long as the slowest one so far
+            let elapsed = now - start;
+            if elapsed + self.longest_generation > Duration::from_secs(secs) {
+                return Some(StopReason::WallClock(elapsed));
+            }
+        }
+        if let Some(max_megasteps) = criteria.max_megasteps {
+            if self.megasteps >= max_megasteps {
+                return Some(StopReason::MegastepBudget(self.megasteps));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,428 @@
+/// Structural order parameters computed from final particle positions
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use vmmc::particle::IsParticle;
+use vmmc::polygons::{calc_polygon_distribution, calc_unitcells};
+use vmmc::tilings::{tiling_from_str, UnitCell};
+use vmmc::vmmc::Vmmc;
+
+pub(crate) fn positions(vmmc: &Vmmc) -> Vec<(f64, f64)> {
+    vmmc.particles()
+        .iter()
+        .map(|p| (p.pos().x(), p.pos().y()))
+        .collect()
+}
+
+pub(crate) fn box_size(vmmc: &Vmmc) -> (f64, f64) {
+    (vmmc.simbox().width(), vmmc.simbox().height())
+}
+
+pub(crate) fn min_image(d: f64, width: f64) -> f64 {
+    d - width * (d / width).round()
+}
+
+// Minimum-image separation from particle `a` to particle `b` in the periodic box
+pub(crate) fn separation(vmmc: &Vmmc, a: usize, b: usize) -> (f64, f64) {
+    let sep = vmmc
+        .simbox()
+        .sep_in_box(*vmmc.particle(a).pos(), *vmmc.particle(b).pos());
+    (sep.x(), sep.y())
+}
+
+// Neighbours of every particle, either its bonded partners or everything within `cutoff`
+pub(crate) fn neighbours(vmmc: &Vmmc, cutoff: Option<f64>) -> Vec<(usize, Vec<usize>)> {
+    let ids: Vec<usize> = vmmc.particles().iter().map(|p| p.id()).collect();
+    ids.iter()
+        .map(|&id| {
+            let neighbours = match cutoff {
+                None => vmmc.determine_interactions(vmmc.particle(id)),
+                Some(cutoff) => ids
+                    .iter()
+                    .copied()
+                    .filter(|&other| {
+                        let (dx, dy) = separation(vmmc, id, other);
+                        other != id && dx * dx + dy * dy <= cutoff * cutoff
+                    })
+                    .collect(),
+            };
+            (id, neighbours)
+        })
+        .collect()
+}
+
+// Local psi_n = 1/k sum_j exp(i n theta_ij) as (re, im), `None` for particles without neighbours
+pub fn local_bond_order(vmmc: &Vmmc, n: u32, cutoff: Option<f64>) -> Vec<Option<(f64, f64)>> {
+    neighbours(vmmc, cutoff)
+        .into_iter()
+        .map(|(id, neighbours)| {
+            if neighbours.is_empty() {
+                return None;
+            }
+            let (re, im) = neighbours.iter().fold((0.0, 0.0), |(re, im), &other| {
+                let (dx, dy) = separation(vmmc, id, other);
+                let theta = n as f64 * dy.atan2(dx);
+                (re + theta.cos(), im + theta.sin())
+            });
+            let k = neighbours.len() as f64;
+            Some((re / k, im / k))
+        })
+        .collect()
+}
+
+// |mean psi_n| over all particles with neighbours, 1 for a single perfect crystal
+pub fn global_bond_order(local: &[Option<(f64, f64)>]) -> f64 {
+    let values: Vec<(f64, f64)> = local.iter().flatten().copied().collect();
+    if values.is_empty() {
+        return 0.0;
+    }
+    let (re, im) = values
+        .iter()
+        .fold((0.0, 0.0), |(re, im), (r, i)| (re + r, im + i));
+    let k = values.len() as f64;
+    ((re / k).powi(2) + (im / k).powi(2)).sqrt()
+}
+
+// Fraction of all particles whose |psi_n| reaches `threshold`
+pub fn ordered_fraction(local: &[Option<(f64, f64)>], threshold: f64) -> f64 {
+    if local.is_empty() {
+        return 0.0;
+    }
+    let ordered = local
+        .iter()
+        .flatten()
+        .filter(|(re, im)| (re * re + im * im).sqrt() >= threshold)
+        .count();
+    ordered as f64 / local.len() as f64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CorrelationFunction {
+    // g(r) in `bins` shells up to `r_max`
+    RadialDistribution { r_max: f64, bins: usize },
+    // S(k) / N over the box's reciprocal lattice, in `bins` shells up to `k_max`
+    StructureFactor { k_max: f64, bins: usize },
+}
+
+impl CorrelationFunction {
+    fn range(&self) -> (f64, usize) {
+        match self {
+            CorrelationFunction::RadialDistribution { r_max, bins } => (*r_max, *bins),
+            CorrelationFunction::StructureFactor { k_max, bins } => (*k_max, *bins),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let (max, bins) = self.range();
+        if bins == 0 {
+            bail!("{self:?} needs at least one bin");
+        }
+        if !(max > 0.0 && max.is_finite()) {
+            bail!("{self:?} needs a positive, finite range");
+        }
+        Ok(())
+    }
+
+    pub fn bin_centres(&self) -> Vec<f64> {
+        let (max, bins) = self.range();
+        let width = max / bins as f64;
+        (0..bins).map(|b| (b as f64 + 0.5) * width).collect()
+    }
+
+    pub fn eval(&self, vmmc: &Vmmc) -> Vec<f64> {
+        self.eval_positions(&positions(vmmc), box_size(vmmc))
+    }
+
+    pub fn eval_positions(&self, positions: &[(f64, f64)], (w, h): (f64, f64)) -> Vec<f64> {
+        let (max, bins) = self.range();
+        let width = max / bins as f64;
+        let n = positions.len();
+        if n < 2 {
+            return vec![0.0; bins];
+        }
+        match self {
+            CorrelationFunction::RadialDistribution { .. } => {
+                let mut counts = vec![0.0; bins];
+                for (i, a) in positions.iter().enumerate() {
+                    for b in &positions[i + 1..] {
+                        let dx = min_image(b.0 - a.0, w);
+                        let dy = min_image(b.1 - a.1, h);
+                        let bin = ((dx * dx + dy * dy).sqrt() / width) as usize;
+                        if bin < bins {
+                            counts[bin] += 2.0;
+                        }
+                    }
+                }
+                // normalise by the ideal-gas count in each shell
+                let density = n as f64 / (w * h);
+                counts
+                    .iter()
+                    .enumerate()
+                    .map(|(bin, count)| {
+                        let (r0, r1) = (bin as f64 * width, (bin + 1) as f64 * width);
+                        count / (n as f64 * density * PI * (r1 * r1 - r0 * r0))
+                    })
+                    .collect()
+            }
+            CorrelationFunction::StructureFactor { .. } => {
+                let mut sums = vec![0.0; bins];
+                let mut counts = vec![0usize; bins];
+                let (dkx, dky) = (2.0 * PI / w, 2.0 * PI / h);
+                let (nx, ny) = ((max / dkx) as i64, (max / dky) as i64);
+                for ix in -nx..=nx {
+                    for iy in 0..=ny {
+                        // k and -k give the same S(k)
+                        if iy == 0 && ix <= 0 {
+                            continue;
+                        }
+                        let (kx, ky) = (ix as f64 * dkx, iy as f64 * dky);
+                        let bin = ((kx * kx + ky * ky).sqrt() / width) as usize;
+                        if bin >= bins {
+                            continue;
+                        }
+                        let (re, im) = positions.iter().fold((0.0, 0.0), |(re, im), p| {
+                            let phase = kx * p.0 + ky * p.1;
+                            (re + phase.cos(), im + phase.sin())
+                        });
+                        sums[bin] += (re * re + im * im) / (n * n) as f64;
+                        counts[bin] += 1;
+                    }
+                }
+                sums.iter()
+                    .zip(&counts)
+                    .map(|(s, c)| if *c == 0 { 0.0 } else { s / *c as f64 })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum CorrelationTarget {
+    // two whitespace- or comma-separated columns (r or k, value), interpolated to the bins
+    File(String),
+    // perfect 4.4.4.4, 3.3.3.3.3.3 or 6.6.6 tiling with the given nearest-neighbour distance
+    Tiling { tiling: String, bond_length: f64 },
+}
+
+impl CorrelationTarget {
+    pub fn curve(&self, function: &CorrelationFunction) -> Result<Vec<f64>> {
+        match self {
+            CorrelationTarget::File(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Reading correlation target {path}"))?;
+                let points = parse_curve(&contents)
+                    .with_context(|| format!("Parsing correlation target {path}"))?;
+                Ok(function
+                    .bin_centres()
+                    .iter()
+                    .map(|x| interpolate(&points, *x))
+                    .collect())
+            }
+            CorrelationTarget::Tiling {
+                tiling,
+                bond_length,
+            } => {
+                let (positions, box_size) = perfect_tiling(tiling, *bond_length, function)?;
+                Ok(function.eval_positions(&positions, box_size))
+            }
+        }
+    }
+}
+
+fn parse_curve(contents: &str) -> Result<Vec<(f64, f64)>> {
+    let mut points = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let cols: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .collect();
+        match cols.as_slice() {
+            [x, y] => points.push((x.parse()?, y.parse()?)),
+            _ => bail!("Expected two columns, got \"{line}\""),
+        }
+    }
+    if points.is_empty() {
+        bail!("No data points");
+    }
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(points)
+}
+
+// Piecewise-linear, clamped to the end points outside the sampled range
+fn interpolate(points: &[(f64, f64)], x: f64) -> f64 {
+    let idx = points.partition_point(|p| p.0 < x);
+    if idx == 0 {
+        return points[0].1;
+    }
+    if idx == points.len() {
+        return points[idx - 1].1;
+    }
+    let ((x0, y0), (x1, y1)) = (points[idx - 1], points[idx]);
+    if x1 == x0 {
+        return y1;
+    }
+    y0 + (y1 - y0) * (x - x0) / (x1 - x0)
+}
+
+// Periodic patch of a regular tiling, large enough to resolve `function`
+fn perfect_tiling(
+    tiling: &str,
+    bond_length: f64,
+    function: &CorrelationFunction,
+) -> Result<(Vec<(f64, f64)>, (f64, f64))> {
+    tiling_from_str(tiling).map_err(|e| anyhow!("Invalid tiling string {tiling}: {e:?}"))?;
+    let a = bond_length;
+    let h = 3f64.sqrt() * a;
+    // rectangular cell and its basis
+    let (cell, basis): ((f64, f64), Vec<(f64, f64)>) = match tiling {
+        "4.4.4.4" => ((a, a), vec![(0.0, 0.0)]),
+        "3.3.3.3.3.3" => ((a, h), vec![(0.0, 0.0), (0.5 * a, 0.5 * h)]),
+        "6.6.6" => (
+            (3.0 * a, h),
+            vec![(0.0, 0.0), (a, 0.0), (1.5 * a, 0.5 * h), (2.5 * a, 0.5 * h)],
+        ),
+        _ => bail!(
+            "Only 4.4.4.4, 3.3.3.3.3.3 and 6.6.6 targets can be generated, \
+             load a target curve for {tiling} from a file instead"
+        ),
+    };
+    let (max, bins) = function.range();
+    let min_size = match function {
+        CorrelationFunction::RadialDistribution { .. } => 2.0 * max,
+        // reciprocal lattice spacing no coarser than the bins
+        CorrelationFunction::StructureFactor { .. } => 2.0 * PI * bins as f64 / max,
+    }
+    .max(20.0 * a);
+    let (nx, ny) = (
+        (min_size / cell.0).ceil() as usize,
+        (min_size / cell.1).ceil() as usize,
+    );
+    let mut positions = Vec::with_capacity(nx * ny * basis.len());
+    for i in 0..nx {
+        for j in 0..ny {
+            for (bx, by) in &basis {
+                positions.push((i as f64 * cell.0 + bx, j as f64 * cell.1 + by));
+            }
+        }
+    }
+    Ok((positions, (nx as f64 * cell.0, ny as f64 * cell.1)))
+}
+
+// 1 / (1 + RMS difference), 1 for a perfect match
+pub fn curve_similarity(actual: &[f64], target: &[f64]) -> f64 {
+    let n = actual.len().min(target.len());
+    if n == 0 {
+        return 0.0;
+    }
+    let mse = actual
+        .iter()
+        .zip(target)
+        .map(|(a, t)| (a - t).powi(2))
+        .sum::<f64>()
+        / n as f64;
+    1.0 / (1.0 + mse.sqrt())
+}
+
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+// Sizes of the connected components of the bond network, largest first
+pub fn cluster_sizes(vmmc: &Vmmc) -> Vec<usize> {
+    let bonds = neighbours(vmmc, None);
+    let index: HashMap<usize, usize> = bonds
+        .iter()
+        .enumerate()
+        .map(|(idx, (id, _))| (*id, idx))
+        .collect();
+    let mut parent: Vec<usize> = (0..bonds.len()).collect();
+    for (idx, (_, neighbours)) in bonds.iter().enumerate() {
+        for other in neighbours {
+            let (a, b) = (find(&mut parent, idx), find(&mut parent, index[other]));
+            parent[a] = b;
+        }
+    }
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    for idx in 0..bonds.len() {
+        *sizes.entry(find(&mut parent, idx)).or_default() += 1;
+    }
+    let mut sizes: Vec<usize> = sizes.into_values().collect();
+    sizes.sort_unstable_by(|a, b| b.cmp(a));
+    sizes
+}
+
+// Number of clusters of each size, indexed by size
+pub fn cluster_size_distribution(sizes: &[usize]) -> Vec<usize> {
+    let mut dist = vec![0; sizes.first().map_or(0, |s| s + 1)];
+    for s in sizes {
+        dist[*s] += 1;
+    }
+    dist
+}
+
+pub fn largest_cluster_fraction(sizes: &[usize]) -> f64 {
+    let total: usize = sizes.iter().sum();
+    if total == 0 {
+        0.0
+    } else {
+        sizes[0] as f64 / total as f64
+    }
+}
+
+// Cluster size seen by an average particle, sum s^2 / sum s
+pub fn weight_averaged_cluster_size(sizes: &[usize]) -> f64 {
+    let total: usize = sizes.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    sizes.iter().map(|s| (s * s) as f64).sum::<f64>() / total as f64
+}
+
+// Polygon side counts that occur in a vertex configuration such as "3.3.4.3.4"
+pub fn tiling_polygons(tiling: &str) -> Vec<usize> {
+    let mut sides: Vec<usize> = tiling.split('.').filter_map(|s| s.parse().ok()).collect();
+    sides.sort_unstable();
+    sides.dedup();
+    sides
+}
+
+// Every particle id, and whether it takes part in at least one matched unit cell
+pub fn unitcell_labels(vmmc: &Vmmc, unitcell: &UnitCell) -> Vec<(usize, bool)> {
+    let covered: HashSet<usize> = calc_unitcells(vmmc, 12, unitcell)
+        .iter()
+        .flat_map(|cell| cell.iter().copied())
+        .collect();
+    vmmc.particles()
+        .iter()
+        .map(|p| (p.id(), covered.contains(&p.id())))
+        .collect()
+}
+
+// Fraction of polygons whose side count does not occur in the target tiling
+pub fn wrong_polygon_fraction(vmmc: &Vmmc, tiling: &str) -> f64 {
+    let allowed = tiling_polygons(tiling);
+    // index `i` of the distribution counts polygons with i + 1 sides
+    let dist = calc_polygon_distribution(vmmc, 12);
+    let total: usize = dist.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let wrong: usize = dist
+        .iter()
+        .enumerate()
+        .filter(|(idx, _)| !allowed.contains(&(idx + 1)))
+        .map(|(_, count)| count)
+        .sum();
+    wrong as f64 / total as f64
+}
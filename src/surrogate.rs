@@ -0,0 +1,352 @@
+/// Gaussian-process surrogate used to screen mutants before they are simulated
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::engine::{Evaluation, EvoEngine, Optimizer};
+use crate::nn::{Dna, LearningStrategy};
+
+fn default_oversample() -> usize {
+    4
+}
+
+fn default_min_observations() -> usize {
+    10
+}
+
+fn default_max_observations() -> usize {
+    500
+}
+
+fn default_length_scale() -> f64 {
+    1.0
+}
+
+fn default_noise() -> f64 {
+    1e-2
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SurrogateConfig {
+    // mutants generated for every child that is actually simulated
+    #[serde(default = "default_oversample")]
+    pub oversample: usize,
+    // evaluated children needed before the surrogate is trusted
+    #[serde(default = "default_min_observations")]
+    pub min_observations: usize,
+    // only the most recent children are kept, fitting is cubic in their number
+    #[serde(default = "default_max_observations")]
+    pub max_observations: usize,
+    // RBF length scale, in units of the per-feature standard deviation
+    #[serde(default = "default_length_scale")]
+    pub length_scale: f64,
+    // observation noise relative to the fitness variance
+    #[serde(default = "default_noise")]
+    pub noise: f64,
+}
+
+impl Default for SurrogateConfig {
+    fn default() -> Self {
+        Self {
+            oversample: default_oversample(),
+            min_observations: default_min_observations(),
+            max_observations: default_max_observations(),
+            length_scale: default_length_scale(),
+            noise: default_noise(),
+        }
+    }
+}
+
+/// The most recent (genome features, fitness) pairs of one genepool
+#[derive(Debug, Clone, Default)]
+pub struct Observations {
+    pub features: Vec<Vec<f64>>,
+    pub fitnesses: Vec<f64>,
+}
+
+impl Observations {
+    // drops the oldest observations beyond `max_len`
+    pub fn push(&mut self, features: Vec<f64>, fitness: f64, max_len: usize) {
+        self.features.push(features);
+        self.fitnesses.push(fitness);
+        let excess = self.len().saturating_sub(max_len);
+        self.features.drain(..excess);
+        self.fitnesses.drain(..excess);
+    }
+
+    pub fn len(&self) -> usize {
+        self.fitnesses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fitnesses.is_empty()
+    }
+
+    pub fn best(&self) -> f64 {
+        self.fitnesses
+            .iter()
+            .cloned()
+            .fold(f64::NEG_INFINITY, f64::max)
+    }
+}
+
+// Lower-triangular `l` with `l * l^T = a`, `None` if `a` is not positive definite
+fn cholesky(a: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let n = a.len();
+    let mut l = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let sum: f64 = (0..j).map(|k| l[i][k] * l[j][k]).sum();
+            if i == j {
+                let d = a[i][i] - sum;
+                if d <= 0.0 {
+                    return None;
+                }
+                l[i][j] = d.sqrt();
+            } else {
+                l[i][j] = (a[i][j] - sum) / l[j][j];
+            }
+        }
+    }
+    Some(l)
+}
+
+// Solves `l * x = b` for lower-triangular `l`
+fn forward_substitute(l: &[Vec<f64>], b: &[f64]) -> Vec<f64> {
+    let mut x = vec![0.0; b.len()];
+    for i in 0..b.len() {
+        let sum: f64 = (0..i).map(|k| l[i][k] * x[k]).sum();
+        x[i] = (b[i] - sum) / l[i][i];
+    }
+    x
+}
+
+// Solves `l^T * x = b` for lower-triangular `l`
+fn back_substitute(l: &[Vec<f64>], b: &[f64]) -> Vec<f64> {
+    let n = b.len();
+    let mut x = vec![0.0; n];
+    for i in (0..n).rev() {
+        let sum: f64 = (i + 1..n).map(|k| l[k][i] * x[k]).sum();
+        x[i] = (b[i] - sum) / l[i][i];
+    }
+    x
+}
+
+/// Zero-mean GP with an RBF kernel on standardised inputs and outputs
+#[derive(Debug, Clone)]
+pub struct GaussianProcess {
+    xs: Vec<Vec<f64>>,
+    x_mean: Vec<f64>,
+    x_scale: Vec<f64>,
+    y_mean: f64,
+    y_scale: f64,
+    length_scale: f64,
+    chol: Vec<Vec<f64>>,
+    alpha: Vec<f64>,
+}
+
+impl GaussianProcess {
+    // `None` if there is nothing to fit or the kernel matrix is singular
+    pub fn fit(xs: &[Vec<f64>], ys: &[f64], length_scale: f64, noise: f64) -> Option<Self> {
+        let n = xs.len();
+        let dim = xs.first()?.len();
+        if n != ys.len() || xs.iter().any(|x| x.len() != dim) {
+            return None;
+        }
+
+        let x_mean: Vec<f64> = (0..dim)
+            .map(|d| xs.iter().map(|x| x[d]).sum::<f64>() / n as f64)
+            .collect();
+        let x_scale: Vec<f64> = (0..dim)
+            .map(|d| {
+                let var = xs.iter().map(|x| (x[d] - x_mean[d]).powi(2)).sum::<f64>() / n as f64;
+                // constant features carry no information, leave them unscaled
+                if var > 0.0 {
+                    var.sqrt()
+                } else {
+                    1.0
+                }
+            })
+            .collect();
+        let y_mean = ys.iter().sum::<f64>() / n as f64;
+        let y_var = ys.iter().map(|y| (y - y_mean).powi(2)).sum::<f64>() / n as f64;
+        let y_scale = if y_var > 0.0 { y_var.sqrt() } else { 1.0 };
+
+        let mut gp = Self {
+            xs: Vec::new(),
+            x_mean,
+            x_scale,
+            y_mean,
+            y_scale,
+            length_scale,
+            chol: Vec::new(),
+            alpha: Vec::new(),
+        };
+        gp.xs = xs.iter().map(|x| gp.standardise(x)).collect();
+
+        let mut k: Vec<Vec<f64>> = gp
+            .xs
+            .iter()
+            .map(|a| gp.xs.iter().map(|b| gp.kernel(a, b)).collect())
+            .collect();
+        for (i, row) in k.iter_mut().enumerate() {
+            row[i] += noise.max(1e-8);
+        }
+        gp.chol = cholesky(&k)?;
+        let ys: Vec<f64> = ys.iter().map(|y| (y - y_mean) / y_scale).collect();
+        gp.alpha = back_substitute(&gp.chol, &forward_substitute(&gp.chol, &ys));
+        Some(gp)
+    }
+
+    fn standardise(&self, x: &[f64]) -> Vec<f64> {
+        x.iter()
+            .zip(self.x_mean.iter().zip(self.x_scale.iter()))
+            .map(|(v, (mean, scale))| (v - mean) / scale)
+            .collect()
+    }
+
+    fn kernel(&self, a: &[f64], b: &[f64]) -> f64 {
+        // squared distance per dimension, so the length scale does not depend on the genome size
+        let dist2 = a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum::<f64>() / a.len() as f64;
+        (-0.5 * dist2 / (self.length_scale * self.length_scale)).exp()
+    }
+
+    // Posterior mean and standard deviation, in fitness units
+    pub fn predict(&self, x: &[f64]) -> (f64, f64) {
+        let x = self.standardise(x);
+        let k: Vec<f64> = self.xs.iter().map(|xi| self.kernel(&x, xi)).collect();
+        let mean: f64 = k.iter().zip(self.alpha.iter()).map(|(k, a)| k * a).sum();
+        let v = forward_substitute(&self.chol, &k);
+        let var = (1.0 - v.iter().map(|v| v * v).sum::<f64>()).max(0.0);
+        (self.y_mean + mean * self.y_scale, var.sqrt() * self.y_scale)
+    }
+}
+
+// Abramowitz & Stegun 7.1.26, accurate to ~1e-7
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let y = 1.0 - poly * (-x * x).exp();
+    y.copysign(x)
+}
+
+fn normal_cdf(z: f64) -> f64 {
+    0.5 * (1.0 + erf(z / std::f64::consts::SQRT_2))
+}
+
+fn normal_pdf(z: f64) -> f64 {
+    (-0.5 * z * z).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+
+/// Expected improvement over `best` for a maximised fitness
+pub fn expected_improvement(mean: f64, std: f64, best: f64) -> f64 {
+    if std <= 0.0 {
+        return (mean - best).max(0.0);
+    }
+    let z = (mean - best) / std;
+    (mean - best) * normal_cdf(z) + std * normal_pdf(z)
+}
+
+/// GP fitted to a genepool's observations, with the best fitness among them
+pub struct Surrogate {
+    gp: GaussianProcess,
+    best: f64,
+}
+
+/// Upper confidence bound for a maximised fitness
+pub fn upper_confidence_bound(mean: f64, std: f64, beta: f64) -> f64 {
+    mean + beta * std
+}
+
+impl EvoEngine {
+    // Only the genetic and island optimizers screen their mutants. Genomes whose features are
+    // not a usable surrogate input are rejected too: Microstate has thousands of network
+    // weights and TimeNet only a seed and an id.
+    pub(crate) fn validate_surrogate(&self) -> Result<()> {
+        if self.surrogate.is_none() {
+            return Ok(());
+        }
+        if !matches!(self.optimizer, Optimizer::Genetic | Optimizer::Islands(_)) {
+            bail!("The surrogate is only used by the Genetic and Islands optimizers");
+        }
+        match self.learning_strategy {
+            LearningStrategy::Fll => Ok(()),
+            LearningStrategy::Timenet | LearningStrategy::MicroState => bail!(
+                "The surrogate needs Fll genomes, {:?} genomes have no useful features",
+                self.learning_strategy
+            ),
+        }
+    }
+
+    // Record the fitness of every child that finished at full fidelity, failed children say
+    // nothing about their genome
+    pub(crate) fn observe(
+        &self,
+        observations: &mut Observations,
+        candidates: &[Dna],
+        children: &[Evaluation],
+    ) {
+        let Some(config) = &self.surrogate else {
+            return;
+        };
+        for (dna, child) in candidates.iter().zip(children) {
+            if let Some(fitness) = child.fitness() {
+                observations.push(dna.genome_features(), fitness, config.max_observations);
+            }
+        }
+    }
+
+    // `None` until enough children have been observed
+    pub(crate) fn fit_surrogate(&self, observations: &Observations) -> Option<Surrogate> {
+        let config = self.surrogate.as_ref()?;
+        if observations.len() < config.min_observations {
+            return None;
+        }
+        let gp = GaussianProcess::fit(
+            &observations.features,
+            &observations.fitnesses,
+            config.length_scale,
+            config.noise,
+        )?;
+        Some(Surrogate {
+            gp,
+            best: observations.best(),
+        })
+    }
+
+    // Spawn `oversample` times as many mutants of `parent` as needed and keep the `num_children`
+    // with the highest expected improvement. Discarded mutants are dropped from the history.
+    pub(crate) fn spawn_screened(
+        &mut self,
+        surrogate: &Surrogate,
+        parent: &Dna,
+        num_children: usize,
+        mutation_factor: f32,
+    ) -> Vec<Dna> {
+        let oversample = self.surrogate.as_ref().map_or(1, |c| c.oversample.max(1));
+        let mut mutants: Vec<(Dna, f64)> = (0..num_children * oversample)
+            .map(|_| {
+                let child = self.spawn_child_with(parent, mutation_factor);
+                let (mean, std) = surrogate.gp.predict(&child.genome_features());
+                let ei = expected_improvement(mean, std, surrogate.best);
+                (child, ei)
+            })
+            .collect();
+        mutants.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        let discarded = mutants.split_off(num_children.min(mutants.len()));
+        let discarded_ids: Vec<usize> = discarded.iter().map(|(c, _)| c.id()).collect();
+        self.history
+            .retain(|(_, child)| !discarded_ids.contains(child));
+
+        let eis: Vec<f64> = mutants.iter().map(|(_, ei)| *ei).collect();
+        log::info!(
+            "Surrogate kept {} of {} mutants of {}: expected improvement = {:?}",
+            mutants.len(),
+            mutants.len() + discarded.len(),
+            parent.id(),
+            eis
+        );
+        mutants.into_iter().map(|(c, _)| c).collect()
+    }
+}
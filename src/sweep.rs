@@ -0,0 +1,279 @@
+/// Grid and Latin-hypercube baselines over flat protocol and `SimParams` parameters
+use anyhow::{anyhow, bail, Result};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use vmmc::{Prng, SimParams};
+
+use crate::engine::EvoEngine;
+use crate::io::{write_sweep_csv, write_sweep_png, write_sweep_points_csv};
+use crate::nn::fixed::FixedProtocol;
+use crate::nn::Dna;
+
+fn default_num() -> usize {
+    5
+}
+
+fn default_replicates() -> usize {
+    1
+}
+
+// Parameters of the flat protocol, anything else is looked up in `SimParams`
+const CHEMICAL_POTENTIAL: &str = "chemical_potential";
+const INTERACTION_ENERGY: &str = "interaction_energy";
+const NUM_MEGASTEPS: &str = "num_megasteps";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SweepParameter {
+    pub name: String,
+    pub min: f64,
+    pub max: f64,
+    // grid points along this parameter, ignored by Latin-hypercube sampling
+    #[serde(default = "default_num")]
+    pub num: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Sampling {
+    #[default]
+    Grid,
+    // number of samples
+    LatinHypercube(usize),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SweepConfig {
+    pub parameters: Vec<SweepParameter>,
+    #[serde(default)]
+    pub sampling: Sampling,
+    // independent seeds per point
+    #[serde(default = "default_replicates")]
+    pub replicates: usize,
+}
+
+/// One simulated replicate of one point
+#[derive(Debug, Clone)]
+pub struct SweepRow {
+    pub point: usize,
+    pub params: Vec<f64>,
+    pub replicate: usize,
+    pub fitness: f64,
+    pub polygons: usize,
+    pub failed: bool,
+}
+
+/// Replicates of one point, failed ones are counted but left out of the mean
+#[derive(Debug, Clone)]
+pub struct SweepPoint {
+    pub params: Vec<f64>,
+    // `None` if every replicate failed
+    pub mean_fitness: Option<f64>,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+impl SweepPoint {
+    fn from_rows(params: Vec<f64>, rows: &[&SweepRow]) -> Self {
+        let fitnesses: Vec<f64> = rows
+            .iter()
+            .filter(|r| !r.failed)
+            .map(|r| r.fitness)
+            .collect();
+        let mean_fitness =
+            (!fitnesses.is_empty()).then(|| fitnesses.iter().sum::<f64>() / fitnesses.len() as f64);
+        Self {
+            params,
+            mean_fitness,
+            succeeded: fitnesses.len(),
+            failed: rows.len() - fitnesses.len(),
+        }
+    }
+}
+
+fn linspace(min: f64, max: f64, num: usize) -> Vec<f64> {
+    if num <= 1 {
+        return vec![min];
+    }
+    (0..num)
+        .map(|i| min + (max - min) * i as f64 / (num - 1) as f64)
+        .collect()
+}
+
+// Set a numeric field of `SimParams` by name, keeping integer fields integral
+fn with_field(sim_params: &SimParams, name: &str, value: f64) -> Result<SimParams> {
+    let mut table = toml::Value::try_from(sim_params)?;
+    let field = table
+        .get_mut(name)
+        .ok_or_else(|| anyhow!("unknown sweep parameter `{name}`"))?;
+    *field = match field {
+        toml::Value::Integer(_) => toml::Value::Integer(value.round() as i64),
+        toml::Value::Float(_) => toml::Value::Float(value),
+        _ => bail!("SimParams field `{name}` is not numeric"),
+    };
+    Ok(table.try_into()?)
+}
+
+impl SweepConfig {
+    pub fn names(&self) -> Vec<String> {
+        self.parameters.iter().map(|p| p.name.clone()).collect()
+    }
+
+    pub fn points(&self, rng: &mut Prng) -> Vec<Vec<f64>> {
+        match self.sampling {
+            Sampling::Grid => {
+                let mut points = vec![Vec::new()];
+                for param in &self.parameters {
+                    let values = linspace(param.min, param.max, param.num);
+                    points = points
+                        .into_iter()
+                        .flat_map(|p| {
+                            values.iter().map(move |v| {
+                                let mut p = p.clone();
+                                p.push(*v);
+                                p
+                            })
+                        })
+                        .collect();
+                }
+                points
+            }
+            Sampling::LatinHypercube(num) => {
+                // one sample per stratum along every parameter, strata paired up at random
+                let mut points = vec![Vec::new(); num];
+                for param in &self.parameters {
+                    let mut strata: Vec<usize> = (0..num).collect();
+                    strata.shuffle(rng);
+                    for (point, stratum) in points.iter_mut().zip(strata) {
+                        let u = (stratum as f64 + rng.random::<f64>()) / num as f64;
+                        point.push(param.min + u * (param.max - param.min));
+                    }
+                }
+                points
+            }
+        }
+    }
+
+    // Width of one heatmap cell along parameter `idx`
+    pub fn cell_width(&self, idx: usize) -> f64 {
+        let param = &self.parameters[idx];
+        let width = match self.sampling {
+            Sampling::Grid if param.num > 1 => (param.max - param.min) / (param.num - 1) as f64,
+            Sampling::Grid => 0.0,
+            Sampling::LatinHypercube(num) => (param.max - param.min) / num.max(1) as f64,
+        };
+        if width > 0.0 {
+            width
+        } else {
+            1.0
+        }
+    }
+
+    // Simulation settings and genome for one point
+    fn setup(&self, engine: &EvoEngine, point: &[f64]) -> Result<(SimParams, Dna)> {
+        let proto = &engine.init_protocol;
+        let mut mu = proto.chemical_potential(0);
+        let mut epsilon = proto.interaction_energy(0);
+        let mut num_megasteps = proto.len();
+        let mut sim_params = engine.sim_params.clone();
+        for (param, value) in self.parameters.iter().zip(point) {
+            match param.name.as_str() {
+                CHEMICAL_POTENTIAL => mu = *value,
+                INTERACTION_ENERGY => epsilon = *value,
+                NUM_MEGASTEPS => num_megasteps = value.round() as usize,
+                name => sim_params = with_field(&sim_params, name, *value)?,
+            }
+        }
+        let dna = Dna::fresh_fixed(FixedProtocol::flat(mu, epsilon, num_megasteps));
+        Ok((sim_params, dna))
+    }
+}
+
+/// Simulate every point of the sweep `replicates` times and write `sweep.csv` and `sweep.png`
+pub fn run_sweep(
+    engine: &EvoEngine,
+    config: &SweepConfig,
+    output_dir: &str,
+    rng: &mut Prng,
+) -> Result<()> {
+    if engine.multi_fidelity.is_some() {
+        bail!("multi_fidelity is not supported by sweeps, every point runs at full fidelity");
+    }
+    if engine.surrogate.is_some() {
+        bail!("The surrogate is not used by sweeps, every point is simulated");
+    }
+    let points = config.points(rng);
+    // build every point first so a bad parameter name fails before anything is simulated
+    let setups = points
+        .iter()
+        .map(|point| {
+            let (sim_params, dna) = config.setup(engine, point)?;
+            let mut point_engine = engine.clone();
+            point_engine.sim_params = sim_params;
+            Ok((point_engine, dna))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    log::info!(
+        "Sweeping {:?} over {} points with {} replicates",
+        config.names(),
+        points.len(),
+        config.replicates
+    );
+
+    let jobs: Vec<(usize, usize, u64)> = (0..points.len())
+        .flat_map(|point| (0..config.replicates).map(move |rep| (point, rep)))
+        .map(|(point, rep)| (point, rep, rng.random()))
+        .collect();
+    let fitness_func = engine.fitness_func.prepare(&engine.sim_params)?;
+    let penalty = engine.fault_tolerance.penalty_fitness;
+    let rows: Vec<SweepRow> = jobs
+        .par_iter()
+        .map(|&(point, replicate, seed)| {
+            let (point_engine, dna) = &setups[point];
+            let p_str = format!("./{output_dir}/{:0>3}/{:0>2}", point, replicate);
+            let child = point_engine.run_child(dna, &fitness_func, seed, &p_str);
+            let row = SweepRow {
+                point,
+                params: points[point].clone(),
+                replicate,
+                fitness: child.summary.as_ref().map_or(penalty, |s| s.fitness),
+                polygons: child.summary.as_ref().map_or(0, |s| s.polygons),
+                failed: child.summary.is_none(),
+            };
+            log::info!(
+                "Point {point} {:?} replicate {replicate}: fitness = {}",
+                row.params,
+                row.fitness
+            );
+            row
+        })
+        .collect();
+
+    let summaries: Vec<SweepPoint> = points
+        .iter()
+        .enumerate()
+        .map(|(idx, params)| {
+            let replicates: Vec<&SweepRow> = rows.iter().filter(|r| r.point == idx).collect();
+            SweepPoint::from_rows(params.clone(), &replicates)
+        })
+        .collect();
+    for (idx, summary) in summaries.iter().enumerate() {
+        if summary.failed > 0 {
+            log::warn!(
+                "Point {idx} {:?}: {} of {} replicates failed",
+                summary.params,
+                summary.failed,
+                summary.failed + summary.succeeded
+            );
+        }
+    }
+
+    write_sweep_csv(&config.names(), &rows, &format!("{output_dir}/sweep.csv"))?;
+    write_sweep_points_csv(
+        &config.names(),
+        &summaries,
+        &format!("{output_dir}/sweep_points.csv"),
+    )?;
+    write_sweep_png(config, &rows, &format!("{output_dir}/sweep.png"));
+    Ok(())
+}
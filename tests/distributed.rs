@@ -0,0 +1,79 @@
+use std::net::TcpListener;
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::Duration;
+
+use l2g::distributed::{run_worker, DistributedConfig, Job, Reply, WorkerPool};
+use l2g::engine::EvoEngine;
+use l2g::fitness::FitnessFunc;
+use l2g::nn::fixed::FixedProtocol;
+use l2g::nn::Dna;
+
+fn free_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("tcp:{}", listener.local_addr().unwrap())
+}
+
+// The worker keeps dialling until the coordinator is listening
+fn spawn_worker(address: String) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for _ in 0..100 {
+            if run_worker(&address).is_ok() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("worker could not reach the coordinator at {address}");
+    })
+}
+
+fn job(engine: &EvoEngine, seed: u64) -> Job {
+    Job {
+        sim_params: engine.sim_params().clone(),
+        dna: Dna::fresh_fixed(FixedProtocol::flat(0.0, 10.0, 2)),
+        seed,
+        fitness_func: FitnessFunc::Random,
+        sim_budget: engine.sim_budget.clone(),
+        protocol_penalty: engine.protocol_penalty.clone(),
+        init_protocol: engine.init_protocol.clone(),
+        max_retries: 0,
+    }
+}
+
+#[test]
+fn localhost_worker_evaluates_jobs() {
+    let address = free_address();
+    let worker = spawn_worker(address.clone());
+    let config = DistributedConfig {
+        listen: address,
+        min_workers: 1,
+        reply_timeout_secs: 600,
+    };
+    let mut pool = WorkerPool::bind(&config).unwrap();
+    assert_eq!(pool.num_workers(), 1);
+
+    let engine = EvoEngine::default();
+    let jobs = (0..3).map(|seed| job(&engine, seed)).collect();
+    let replies = pool.evaluate(jobs, &AtomicBool::new(false));
+    assert_eq!(replies.len(), 3);
+    for reply in &replies {
+        match reply {
+            Reply::Done { summary, megasteps } => {
+                assert_eq!(summary.megasteps, 2);
+                assert_eq!(*megasteps, 2);
+            }
+            other => panic!("expected a summary, got {other:?}"),
+        }
+    }
+
+    // a cancelled run sends out no jobs
+    let replies = pool.evaluate(vec![job(&engine, 3)], &AtomicBool::new(true));
+    assert!(matches!(
+        replies.as_slice(),
+        [Reply::Cancelled { megasteps: 0 }]
+    ));
+
+    // hanging up lets the worker return
+    drop(pool);
+    worker.join().unwrap();
+}